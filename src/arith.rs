@@ -0,0 +1,538 @@
+use crate::error::ShellError;
+use crate::interpreter::ExecContext;
+
+/// How many variables holding expressions are evaluated inside each other
+/// before giving up, as with `a=b b=a`.
+const MAX_DEPTH: usize = 64;
+
+/// Operators, longest first so that `<<=` is not read as `<` and `<=`.
+const OPERATORS: [&str; 39] = [
+    "<<=", ">>=", "**", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "*=", "/=",
+    "%=", "+=", "-=", "&=", "^=", "|=", "+", "-", "*", "/", "%", "<", ">", "&", "^", "|", "!", "~",
+    "?", ":", "=", ",", "(", ")",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Tok {
+    Num(i64),
+    Name(String),
+    Op(&'static str),
+}
+
+/// Binding power of a binary operator, higher binds tighter as in C.
+fn precedence(op: &str) -> Option<u8> {
+    Some(match op {
+        "||" => 1,
+        "&&" => 2,
+        "|" => 3,
+        "^" => 4,
+        "&" => 5,
+        "==" | "!=" => 6,
+        "<" | "<=" | ">" | ">=" => 7,
+        "<<" | ">>" => 8,
+        "+" | "-" => 9,
+        "*" | "/" | "%" => 10,
+        "**" => 11,
+        _ => return None,
+    })
+}
+
+/// Whether `op` assigns to the variable before it, as `=` and `+=` do.
+fn is_assignment(op: &str) -> bool {
+    matches!(
+        op,
+        "=" | "*=" | "/=" | "%=" | "+=" | "-=" | "<<=" | ">>=" | "&=" | "^=" | "|="
+    )
+}
+
+/// Reads an integer constant: decimal, octal with a leading `0`, hexadecimal
+/// with a leading `0x`, or `base#digits` for bases from 2 to 64.
+fn number(text: &str) -> Result<i64, String> {
+    let (base, digits) = match text.split_once('#') {
+        Some((base, digits)) => match base.parse::<u32>() {
+            Ok(base @ 2..=64) => (base, digits),
+            _ => return Err(format!("{}: invalid arithmetic base", text)),
+        },
+        None if text.starts_with("0x") || text.starts_with("0X") => (16, &text[2..]),
+        None if text.len() > 1 && text.starts_with('0') => (8, &text[1..]),
+        None => (10, text),
+    };
+    if digits.is_empty() {
+        return Err(format!("{}: invalid number", text));
+    }
+    let mut value: i64 = 0;
+    for c in digits.chars() {
+        // Up to base 36 letters are digits in either case, above it lowercase
+        // letters come before uppercase ones, followed by `@` and `_`.
+        let digit = match c {
+            '0'..='9' => c as u32 - '0' as u32,
+            'a'..='z' => c as u32 - 'a' as u32 + 10,
+            'A'..='Z' if base <= 36 => c as u32 - 'A' as u32 + 10,
+            'A'..='Z' => c as u32 - 'A' as u32 + 36,
+            '@' => 62,
+            '_' => 63,
+            _ => u32::MAX,
+        };
+        if digit >= base {
+            return Err(format!("{}: value too great for base", text));
+        }
+        value = value.wrapping_mul(base as i64).wrapping_add(digit as i64);
+    }
+    Ok(value)
+}
+
+fn tokenize(expr: &str) -> Result<Vec<Tok>, String> {
+    let mut toks = Vec::new();
+    let mut rest = expr.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = if c.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && !matches!(c, '#' | '@' | '_'))
+                .unwrap_or(rest.len());
+            toks.push(Tok::Num(number(&rest[..len])?));
+            len
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            toks.push(Tok::Name(String::from(&rest[..len])));
+            len
+        } else {
+            match OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+                Some(op) => {
+                    toks.push(Tok::Op(op));
+                    op.len()
+                }
+                None => {
+                    return Err(format!(
+                        "syntax error: invalid arithmetic operator (error token is \"{}\")",
+                        rest
+                    ))
+                }
+            }
+        };
+        rest = rest[len..].trim_start();
+    }
+    Ok(toks)
+}
+
+/// A recursive descent evaluator over the tokens of one expression.
+struct Eval<'a> {
+    ctx: &'a mut ExecContext,
+    toks: Vec<Tok>,
+    current: usize,
+    /// Set while going over the side of `&&`, `||` or `?:` that is not taken,
+    /// which is parsed without assigning anything or failing on a division by 0.
+    skip: bool,
+    depth: usize,
+}
+
+impl Eval<'_> {
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.toks.get(self.current) {
+            Some(Tok::Op(op)) => Some(op),
+            _ => None,
+        }
+    }
+    fn eat(&mut self, op: &str) -> bool {
+        if self.peek_op() == Some(op) {
+            self.current += 1;
+            return true;
+        }
+        false
+    }
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        match self.eat(op) {
+            true => Ok(()),
+            false => Err(format!("syntax error: \"{}\" expected", op)),
+        }
+    }
+
+    /// The value of a variable, which can hold an expression of its own. Unset
+    /// and empty variables are 0.
+    fn value(&mut self, name: &str) -> Result<i64, String> {
+        let text = self.ctx.get_var(name).unwrap_or_default();
+        let text = text.trim();
+        if text.is_empty() {
+            return Ok(0);
+        }
+        if let Ok(value) = text.parse::<i64>() {
+            return Ok(value);
+        }
+        if self.depth >= MAX_DEPTH {
+            return Err(String::from("expression recursion level exceeded"));
+        }
+        let toks = tokenize(text)?;
+        let mut inner = Eval {
+            ctx: &mut *self.ctx,
+            toks,
+            current: 0,
+            skip: self.skip,
+            depth: self.depth + 1,
+        };
+        inner.all()
+    }
+    fn store(&mut self, name: &str, value: i64) {
+        if !self.skip {
+            self.ctx.set_var(name, value.to_string());
+        }
+    }
+
+    fn apply(&self, op: &str, left: i64, right: i64) -> Result<i64, String> {
+        Ok(match op {
+            "+" => left.wrapping_add(right),
+            "-" => left.wrapping_sub(right),
+            "*" => left.wrapping_mul(right),
+            "/" | "%" if right == 0 => match self.skip {
+                true => 0,
+                false => return Err(String::from("division by 0")),
+            },
+            "/" => left.wrapping_div(right),
+            "%" => left.wrapping_rem(right),
+            "**" if right < 0 => match self.skip {
+                true => 0,
+                false => return Err(String::from("exponent less than 0")),
+            },
+            "**" => left.wrapping_pow(right.min(u32::MAX as i64) as u32),
+            "<<" => left.wrapping_shl(right as u32),
+            ">>" => left.wrapping_shr(right as u32),
+            "&" => left & right,
+            "^" => left ^ right,
+            "|" => left | right,
+            "<" => (left < right) as i64,
+            "<=" => (left <= right) as i64,
+            ">" => (left > right) as i64,
+            ">=" => (left >= right) as i64,
+            "==" => (left == right) as i64,
+            "!=" => (left != right) as i64,
+            "&&" => (left != 0 && right != 0) as i64,
+            "||" => (left != 0 || right != 0) as i64,
+            _ => return Err(format!("syntax error: invalid operator \"{}\"", op)),
+        })
+    }
+
+    /// The whole expression, which has to use up every token.
+    fn all(&mut self) -> Result<i64, String> {
+        if self.toks.is_empty() {
+            return Ok(0);
+        }
+        let value = self.comma()?;
+        match self.toks.get(self.current) {
+            None => Ok(value),
+            Some(_) => Err(String::from("syntax error in expression")),
+        }
+    }
+
+    /// comma → assign ( "," assign )*
+    fn comma(&mut self) -> Result<i64, String> {
+        let mut value = self.assign()?;
+        while self.eat(",") {
+            value = self.assign()?;
+        }
+        Ok(value)
+    }
+
+    /// assign → NAME assignment_operator assign | ternary
+    fn assign(&mut self) -> Result<i64, String> {
+        let (Some(Tok::Name(name)), Some(Tok::Op(op))) =
+            (self.toks.get(self.current), self.toks.get(self.current + 1))
+        else {
+            return self.ternary();
+        };
+        if !is_assignment(op) {
+            return self.ternary();
+        }
+        let (name, op) = (name.clone(), *op);
+        self.current += 2;
+        let right = self.assign()?;
+        let value = match op {
+            "=" => right,
+            _ => {
+                let left = self.value(&name)?;
+                self.apply(&op[..op.len() - 1], left, right)?
+            }
+        };
+        self.store(&name, value);
+        Ok(value)
+    }
+
+    /// ternary → binary ( "?" comma ":" assign )?
+    fn ternary(&mut self) -> Result<i64, String> {
+        let cond = self.binary(1)?;
+        if !self.eat("?") {
+            return Ok(cond);
+        }
+        let skip = self.skip;
+        self.skip = skip || cond == 0;
+        let then = self.comma()?;
+        self.expect(":")?;
+        self.skip = skip || cond != 0;
+        let otherwise = self.assign()?;
+        self.skip = skip;
+        Ok(if cond != 0 { then } else { otherwise })
+    }
+
+    /// Precedence climbing over the binary operators, `**` associates to the
+    /// right and every other operator to the left.
+    fn binary(&mut self, min_prec: u8) -> Result<i64, String> {
+        let mut left = self.unary()?;
+        while let Some(op) = self.peek_op() {
+            let Some(prec) = precedence(op) else {
+                break;
+            };
+            if prec < min_prec {
+                break;
+            }
+            self.current += 1;
+            let right = match op {
+                // The right side of `&&` and `||` is only evaluated when it can
+                // still change the result.
+                "&&" | "||" => {
+                    let skip = self.skip;
+                    self.skip = skip || (op == "&&") == (left == 0);
+                    let right = self.binary(prec + 1);
+                    self.skip = skip;
+                    right?
+                }
+                "**" => self.binary(prec)?,
+                _ => self.binary(prec + 1)?,
+            };
+            left = self.apply(op, left, right)?;
+        }
+        Ok(left)
+    }
+
+    /// unary → ( "+" | "-" | "!" | "~" ) unary | ( "++" | "--" ) NAME | postfix
+    fn unary(&mut self) -> Result<i64, String> {
+        let Some(op) = self.peek_op() else {
+            return self.postfix();
+        };
+        match op {
+            "++" | "--" => {
+                if let Some(Tok::Name(name)) = self.toks.get(self.current + 1) {
+                    let name = name.clone();
+                    self.current += 2;
+                    let value = self.value(&name)?;
+                    let value = match op {
+                        "++" => value.wrapping_add(1),
+                        _ => value.wrapping_sub(1),
+                    };
+                    self.store(&name, value);
+                    return Ok(value);
+                }
+                // Without a variable after it `--1` is two signs, as in `-(-1)`.
+                self.current += 1;
+                self.unary()
+            }
+            "+" => {
+                self.current += 1;
+                self.unary()
+            }
+            "-" => {
+                self.current += 1;
+                Ok(self.unary()?.wrapping_neg())
+            }
+            "!" => {
+                self.current += 1;
+                Ok((self.unary()? == 0) as i64)
+            }
+            "~" => {
+                self.current += 1;
+                Ok(!self.unary()?)
+            }
+            _ => self.postfix(),
+        }
+    }
+
+    /// postfix → NAME ( "++" | "--" )? | NUMBER | "(" comma ")"
+    fn postfix(&mut self) -> Result<i64, String> {
+        match self.toks.get(self.current).cloned() {
+            Some(Tok::Num(value)) => {
+                self.current += 1;
+                Ok(value)
+            }
+            Some(Tok::Name(name)) => {
+                self.current += 1;
+                let value = self.value(&name)?;
+                match self.peek_op() {
+                    Some("++") => self.store(&name, value.wrapping_add(1)),
+                    Some("--") => self.store(&name, value.wrapping_sub(1)),
+                    _ => return Ok(value),
+                }
+                self.current += 1;
+                Ok(value)
+            }
+            Some(Tok::Op("(")) => {
+                self.current += 1;
+                let value = self.comma()?;
+                self.expect(")")?;
+                Ok(value)
+            }
+            Some(Tok::Op(op)) => Err(format!(
+                "syntax error: operand expected (error token is \"{}\")",
+                op
+            )),
+            None => Err(String::from("syntax error: operand expected")),
+        }
+    }
+}
+
+impl ExecContext {
+    /// Evaluates an arithmetic expression whose parameters have already been
+    /// expanded, with 64-bit integers that wrap around on overflow.
+    pub fn arithmetic(&mut self, expr: &str) -> Result<i64, ShellError> {
+        let result = tokenize(expr).and_then(|toks| {
+            Eval {
+                ctx: self,
+                toks,
+                current: 0,
+                skip: false,
+                depth: 0,
+            }
+            .all()
+        });
+        result.map_err(|message| ShellError::expansion(format!("{}: {}", expr.trim(), message)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interpreter::ExecContext;
+    use std::path::Path;
+
+    /// A shell without any variables, so that names in expressions start unset.
+    fn shell() -> ExecContext {
+        let mut ctx = ExecContext::new(Path::new("/"), vec![String::from("wts")]);
+        ctx.vars.clear();
+        ctx
+    }
+
+    fn eval(expr: &str) -> i64 {
+        shell()
+            .arithmetic(expr)
+            .unwrap_or_else(|e| panic!("{}: {}", expr, e))
+    }
+
+    #[test]
+    fn operators() {
+        let cases = [
+            // Precedence and grouping.
+            ("1 + 2 * 3", 7),
+            ("(1 + 2) * 3", 9),
+            ("10 - 4 - 3", 3),
+            ("1 + 2 == 3", 1),
+            ("1 | 2 ^ 3 & 4", 3),
+            // `**` binds tighter than unary minus and is right associative.
+            ("2 ** 3 ** 2", 512),
+            ("-2 ** 2", 4),
+            ("2 ** 0", 1),
+            // Division truncates toward zero.
+            ("7 / 2", 3),
+            ("-7 / 2", -3),
+            ("-7 % 3", -1),
+            // Shifts.
+            ("1 << 4", 16),
+            ("-16 >> 2", -4),
+            // Bitwise and logical operators.
+            ("5 & 3", 1),
+            ("5 | 3", 7),
+            ("5 ^ 3", 6),
+            ("~0", -1),
+            ("!5", 0),
+            ("!0", 1),
+            ("1 && 0", 0),
+            ("0 || 3", 1),
+            // Comparisons.
+            ("3 < 4", 1),
+            ("4 <= 3", 0),
+            ("4 > 3", 1),
+            ("3 >= 3", 1),
+            ("2 == 2", 1),
+            ("2 != 2", 0),
+            // The conditional operator nests to the right.
+            ("1 ? 2 : 3", 2),
+            ("0 ? 2 : 0 ? 4 : 5", 5),
+            ("1, 2", 2),
+            // Numbers in other bases.
+            ("0x1f", 31),
+            ("010", 8),
+            ("2#101", 5),
+            ("16#ff", 255),
+            ("36#z", 35),
+            ("64#Z", 61),
+            ("64#@", 62),
+            ("64#_", 63),
+            // Overflow wraps around.
+            ("9223372036854775807 + 1", i64::MIN),
+            ("-9223372036854775807 - 1 - 1", i64::MAX),
+            ("(-9223372036854775807 - 1) / -1", i64::MIN),
+            // The side that is not taken is not evaluated.
+            ("0 && 1 / 0", 0),
+            ("1 || 1 / 0", 1),
+            ("1 ? 2 : 1 / 0", 2),
+        ];
+        for (expr, expected) in cases {
+            assert_eq!(eval(expr), expected, "{}", expr);
+        }
+    }
+
+    #[test]
+    fn assignments() {
+        let cases = [
+            ("a = 9", 9, 9),
+            ("a += 3", 8, 8),
+            ("a -= 1", 4, 4),
+            ("a *= 2", 10, 10),
+            ("a /= 2", 2, 2),
+            ("a %= 3", 2, 2),
+            ("a <<= 2", 20, 20),
+            ("a >>= 1", 2, 2),
+            ("a &= 4", 4, 4),
+            ("a |= 2", 7, 7),
+            ("a ^= 1", 4, 4),
+            ("a++", 5, 6),
+            ("a--", 5, 4),
+            ("++a", 6, 6),
+            ("--a", 4, 4),
+            ("b = a = 2", 2, 2),
+        ];
+        for (expr, value, after) in cases {
+            let mut ctx = shell();
+            ctx.set_var("a", String::from("5"));
+            assert_eq!(ctx.arithmetic(expr).unwrap(), value, "{}", expr);
+            assert_eq!(ctx.get_var("a").unwrap(), after.to_string(), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn variables() {
+        let mut ctx = shell();
+        ctx.set_var("b", String::from("a * 2"));
+        ctx.set_var("a", String::from("4"));
+        assert_eq!(ctx.arithmetic("b + 1").unwrap(), 9);
+        assert_eq!(ctx.arithmetic("unset + 1").unwrap(), 1);
+        ctx.set_var("a", String::from("b"));
+        assert!(ctx.arithmetic("a").is_err());
+    }
+
+    #[test]
+    fn errors() {
+        let cases = [
+            ("1/0", "division by 0"),
+            ("5 % 0", "division by 0"),
+            ("2 ** -1", "exponent less than 0"),
+            ("2#102", "value too great for base"),
+            ("08", "value too great for base"),
+            ("65#1", "invalid arithmetic base"),
+            ("1 +", "operand expected"),
+            ("(1", "\")\" expected"),
+            ("1 2", "syntax error"),
+            ("1 = 2", "syntax error"),
+        ];
+        for (expr, message) in cases {
+            let error = shell().arithmetic(expr).unwrap_err().to_string();
+            assert!(error.starts_with(expr), "{}: {}", expr, error);
+            assert!(error.contains(message), "{}: {}", expr, error);
+        }
+    }
+}
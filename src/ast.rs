@@ -1,24 +1,129 @@
+use crate::error::Span;
+use std::sync::Arc;
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug)]
 pub struct AST {
     pub exprs: Vec<Expr>,
+    /// The source the program was parsed from, which the spans point into.
+    pub source: Arc<str>,
 }
 
 #[derive(Debug)]
-pub struct Expr {
-    pub kind: Kind,
-    pub flags: Vec<String>,
-    pub args: Vec<String>,
-    pub symbol: Symbol,
-    pub value: Option<String>,
-    pub left: Option<AST>,
-    pub right: Option<AST>,
+pub enum Expr {
+    /// One or more commands whose stdout/stdin are connected with `|`.
+    Pipeline(Vec<Command>),
+    /// Two expressions joined by a conditional operator such as `&&`.
+    Binary {
+        symbol: Symbol,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+    /// An expression followed by `&`, run without waiting for it. The body holds
+    /// the expression on its own so that it can outlive the program it was
+    /// started from.
+    Background { body: Arc<AST>, command: String },
+}
+
+#[derive(Debug)]
+pub struct Command {
+    pub kind: CommandKind,
+    pub redirects: Vec<Redirect>,
+    pub span: Span,
 }
 
-#[derive(Debug, PartialEq, Eq)]
-pub enum Kind {
-    //None,
-    Expr,
-    Value,
+#[derive(Debug)]
+pub enum CommandKind {
+    /// Variable assignments followed by a program name and its arguments, words
+    /// are kept as written and only expanded when the command runs.
+    Simple {
+        assigns: Vec<(String, String)>,
+        words: Vec<String>,
+    },
+    /// A parenthesized program run in its own copy of the execution context.
+    Subshell(AST),
+    /// A program in braces, run in the shell itself.
+    Group(AST),
+    /// `if` with its `elif` branches as pairs of a condition and a body, and the
+    /// `else` body.
+    If {
+        branches: Vec<(AST, AST)>,
+        otherwise: Option<AST>,
+    },
+    /// A `while` loop, or an `until` loop running as long as `cond` fails.
+    Loop { until: bool, cond: AST, body: AST },
+    /// A `for` loop over `words`, or over the positional parameters when the
+    /// `in` part is left out.
+    For {
+        name: String,
+        words: Option<Vec<String>>,
+        body: AST,
+    },
+    /// A `case` command matching `word` against the patterns of its arms in
+    /// order.
+    Case { word: String, arms: Vec<CaseArm> },
+    /// A `[[ ... ]]` command, which succeeds when the condition holds.
+    Cond(Cond),
+    /// A `(( ... ))` command holding the expression between the parentheses,
+    /// which succeeds when the expression is not 0.
+    Arithmetic(String),
+    /// A function definition, whose body is a single compound command along
+    /// with its redirections. Running it only stores the body under `name`.
+    Function { name: String, body: Arc<AST> },
+}
+
+/// The condition of a `[[ ... ]]` command. Words are kept as written and
+/// expanded when it is evaluated, without field splitting or pathname expansion.
+#[derive(Debug)]
+pub enum Cond {
+    /// A lone word, true when it expands to a non-empty string.
+    Word(String),
+    /// A test on one word such as `-f file`.
+    Unary {
+        op: String,
+        word: String,
+    },
+    /// A comparison such as `$a == *.txt`, the right side of `=~` is kept as
+    /// written in the source.
+    Binary {
+        op: String,
+        left: String,
+        right: String,
+    },
+    Not(Box<Cond>),
+    And(Box<Cond>, Box<Cond>),
+    Or(Box<Cond>, Box<Cond>),
+}
+
+#[derive(Debug)]
+pub struct CaseArm {
+    /// The `|` separated patterns, kept as written like the words of a command.
+    pub patterns: Vec<String>,
+    pub body: AST,
+    pub end: CaseEnd,
+}
+
+/// The operator ending a `case` arm, which decides what happens after its body
+/// ran.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaseEnd {
+    /// `;;` leaves the `case`.
+    Break,
+    /// `;&` runs the body of the next arm without testing its patterns.
+    FallThrough,
+    /// `;;&` goes on testing the patterns of the arms after it.
+    Continue,
+}
+
+#[derive(Debug)]
+pub struct Redirect {
+    /// The file descriptor being redirected, e.g. `2` in `2>&1`.
+    pub fd: i32,
+    pub symbol: Symbol,
+    /// The file name, heredoc body, here-string or descriptor number being
+    /// redirected to.
+    pub target: String,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -26,14 +131,20 @@ pub enum Symbol {
     // Single-character tokens.
     RedirLeft,
     RedirRight,
+    ClobberRedirRight,
+    ReadWriteRedir,
     DoubleRedirRight,
     DoubleRedirLeft,
-    //DupRedir,
+    /// A heredoc whose delimiter was quoted, so its body is not expanded.
+    DoubleRedirLeftQuoted,
+    HereString,
+    DupRedirLeft,
+    DupRedirRight,
+    AndRedirRight,
+    AndDoubleRedirRight,
     Pipe,
-    // Literals.
-    Cmd,
-    String,
-    File,
+    AndIf,
+    OrIf,
     // Keywords.
 
     //SCRIPT,
@@ -45,5 +156,15 @@ pub enum Symbol {
     //TRUE,
     //VAR,
     //WHILE,
-    None,
+}
+
+impl Symbol {
+    /// Binding power of a binary operator, higher binds tighter.
+    pub fn precedence(&self) -> u8 {
+        match self {
+            Symbol::AndIf | Symbol::OrIf => 1,
+            Symbol::Pipe => 2,
+            _ => 0,
+        }
+    }
 }
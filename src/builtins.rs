@@ -0,0 +1,497 @@
+use crate::expand::is_name;
+use crate::interpreter::{ExecContext, Flow, Io};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// Options that can be turned on with `set -o` and off with `set +o`.
+const OPTIONS: [&str; 7] = [
+    "dotglob",
+    "emacs",
+    "failglob",
+    "noclobber",
+    "noglob",
+    "nullglob",
+    "vi",
+];
+
+/// Quotes `value` so that it reads back as the same word.
+pub fn quote(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | '$' | '`') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Resolves the `.` and `..` components of an absolute path without looking at
+/// the file system, so `..` goes back over a symlink instead of out of its
+/// target.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normal = PathBuf::from("/");
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                normal.pop();
+            }
+            Component::Normal(part) => normal.push(part),
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    normal
+}
+
+impl ExecContext {
+    /// Whether `words` names a command that the shell runs itself.
+    pub fn is_builtin(&self, words: &[String]) -> bool {
+        match words[0].as_str() {
+            ":" | "bg" | "break" | "cd" | "continue" | "exit" | "export" | "false" | "fg"
+            | "jobs" | "kill" | "local" | "return" | "unset" | "set" | "test" | "trap" | "true"
+            | "wait" | "[" => true,
+            // `env` with arguments runs a command, which is left to the real `env`.
+            "env" => words.len() == 1,
+            _ => false,
+        }
+    }
+
+    /// Runs a builtin on the given streams and returns its exit status.
+    /// Assignments written before it are exported until it returns, unless the
+    /// builtin assigns the variable itself.
+    pub fn run_builtin(
+        &mut self,
+        assigns: Vec<(String, String)>,
+        words: &[String],
+        io: &Io,
+    ) -> i32 {
+        let saved: Vec<_> = assigns
+            .into_iter()
+            .map(|(name, value)| {
+                let old = self.vars.insert(name.clone(), value.clone());
+                let exported = !self.exported.insert(name.clone());
+                (name, value, old, exported)
+            })
+            .collect();
+        let status = self.dispatch_builtin(words, io);
+        // The variables named by `export`, `local` or `unset` are theirs to set.
+        let targets: Vec<&str> = match words[0].as_str() {
+            "unset" if words.iter().any(|arg| arg == "-f") => Vec::new(),
+            "export" | "local" | "unset" => words[1..]
+                .iter()
+                .filter(|arg| !arg.starts_with('-'))
+                .map(|arg| arg.split_once('=').map_or(arg.as_str(), |(name, _)| name))
+                .collect(),
+            _ => Vec::new(),
+        };
+        for (name, value, old, exported) in saved.into_iter().rev() {
+            if targets.contains(&name.as_str()) || self.vars.get(&name) != Some(&value) {
+                if words[0] != "export" && !exported {
+                    self.exported.remove(&name);
+                }
+                // `local` saved the temporary value, the function has to return
+                // to the one from before it.
+                if let Some(saved) = self.locals.last_mut() {
+                    if words[0] == "local" && saved.get(&name) == Some(&Some(value)) {
+                        saved.insert(name, old);
+                    }
+                }
+                continue;
+            }
+            match old {
+                Some(value) => self.set_var(&name, value),
+                None => {
+                    self.vars.remove(&name);
+                }
+            }
+            if !exported {
+                self.exported.remove(&name);
+            }
+        }
+        status
+    }
+
+    /// Runs the builtin named by `words[0]` with the rest as its arguments.
+    fn dispatch_builtin(&mut self, words: &[String], io: &Io) -> i32 {
+        let (mut out, mut err) = match (io.file(1), io.file(2)) {
+            (Ok(out), Ok(err)) => (out, err),
+            (Err(e), _) | (_, Err(e)) => {
+                eprintln!("{}: {}", words[0], e);
+                return 1;
+            }
+        };
+        let args = &words[1..];
+        let status = match words[0].as_str() {
+            "export" => self.export(args, &mut out, &mut err),
+            "unset" => self.unset(args, &mut err),
+            "env" => self.env(&mut out),
+            "set" => self.set(args, &mut out, &mut err),
+            "cd" => self.cd(args, &mut out, &mut err),
+            "exit" => self.exit(args, &mut err),
+            "local" => self.local(args, &mut err),
+            "test" | "[" => self.test_builtin(&words[0], args, io, &mut err),
+            "return" => self.ret(args, &mut err),
+            "break" | "continue" => self.jump(&words[0], args, &mut err),
+            "jobs" => self.jobs(args, &mut out),
+            "fg" => self.fg(args, &mut out, &mut err),
+            "bg" => self.bg(args, &mut out, &mut err),
+            "wait" => self.wait(args, &mut err),
+            "trap" => self.trap(args, &mut out, &mut err),
+            "kill" => self.kill(args, &mut out, &mut err),
+            ":" | "true" => Ok(0),
+            "false" => Ok(1),
+            _ => Ok(127),
+        };
+        status.unwrap_or_else(|e| {
+            let _ = writeln!(err, "{}: {}", words[0], e);
+            1
+        })
+    }
+
+    /// `export [-n] [-p] [NAME[=value]...]`
+    fn export(
+        &mut self,
+        args: &[String],
+        out: &mut dyn Write,
+        err: &mut dyn Write,
+    ) -> io::Result<i32> {
+        let mut unexport = false;
+        let mut names = Vec::new();
+        for arg in args {
+            match arg.as_str() {
+                "-n" => unexport = true,
+                "-p" => {}
+                _ => names.push(arg),
+            }
+        }
+        if names.is_empty() {
+            for (name, value) in self.environment() {
+                writeln!(out, "export {}={}", name, quote(value))?;
+            }
+            return Ok(0);
+        }
+        let mut status = 0;
+        for arg in names {
+            let (name, value) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (arg.as_str(), None),
+            };
+            if !is_name(name) {
+                writeln!(err, "export: `{}': not a valid identifier", arg)?;
+                status = 1;
+                continue;
+            }
+            if let Some(value) = value {
+                self.set_var(name, String::from(value));
+            }
+            if unexport {
+                self.exported.remove(name);
+            } else {
+                self.exported.insert(String::from(name));
+            }
+        }
+        Ok(status)
+    }
+
+    /// `unset [-v|-f] NAME...`, `-f` removes functions instead of variables.
+    fn unset(&mut self, args: &[String], err: &mut dyn Write) -> io::Result<i32> {
+        let mut status = 0;
+        let functions = args.iter().any(|arg| arg == "-f");
+        for name in args.iter().filter(|arg| *arg != "-v" && *arg != "-f") {
+            if functions {
+                self.functions.remove(name.as_str());
+                continue;
+            }
+            if !is_name(name) {
+                writeln!(err, "unset: `{}': not a valid identifier", name)?;
+                status = 1;
+                continue;
+            }
+            self.vars.remove(name);
+            self.arrays.remove(name);
+            self.exported.remove(name);
+        }
+        Ok(status)
+    }
+
+    /// `set [-f|+f] [-o|+o [option]] [--] [arg...]`, with no arguments the
+    /// variables are listed. The words after the options become the positional
+    /// parameters.
+    fn set(
+        &mut self,
+        args: &[String],
+        out: &mut dyn Write,
+        err: &mut dyn Write,
+    ) -> io::Result<i32> {
+        if args.is_empty() {
+            let mut vars: Vec<_> = self.vars.iter().collect();
+            vars.sort();
+            for (name, value) in vars {
+                writeln!(out, "{}={}", name, quote(value))?;
+            }
+            return Ok(0);
+        }
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let (on, name) = match arg.as_str() {
+                "-f" => (true, Some(String::from("noglob"))),
+                "+f" => (false, Some(String::from("noglob"))),
+                "-C" => (true, Some(String::from("noclobber"))),
+                "+C" => (false, Some(String::from("noclobber"))),
+                "-o" => (true, args.next().cloned()),
+                "+o" => (false, args.next().cloned()),
+                // The remaining words replace the positional parameters.
+                "--" => {
+                    self.args.truncate(1);
+                    self.args.extend(args.cloned());
+                    return Ok(0);
+                }
+                arg if !arg.starts_with(['-', '+']) => {
+                    self.args.truncate(1);
+                    self.args.push(String::from(arg));
+                    self.args.extend(args.cloned());
+                    return Ok(0);
+                }
+                _ => {
+                    writeln!(err, "set: {}: invalid option", arg)?;
+                    return Ok(2);
+                }
+            };
+            let Some(name) = name else {
+                for option in OPTIONS {
+                    let state = if self.option(option) { "on" } else { "off" };
+                    writeln!(out, "{:<15} {}", option, state)?;
+                }
+                continue;
+            };
+            if !OPTIONS.contains(&name.as_str()) {
+                writeln!(err, "set: {}: invalid option name", name)?;
+                return Ok(2);
+            }
+            // The prompt has one set of keys at a time.
+            if on && (name == "vi" || name == "emacs") {
+                self.options.remove("vi");
+                self.options.remove("emacs");
+            }
+            if on {
+                self.options.insert(name);
+            } else {
+                self.options.remove(&name);
+            }
+        }
+        Ok(0)
+    }
+
+    /// `cd [-L|-P] [dir]`, with no directory it goes to `HOME` and `-` goes back
+    /// to `OLDPWD`. Relative directories are looked up in `CDPATH` first.
+    fn cd(&mut self, args: &[String], out: &mut dyn Write, err: &mut dyn Write) -> io::Result<i32> {
+        let mut physical = false;
+        let mut args = args.iter().peekable();
+        while let Some(arg) = args.next_if(|arg| arg.starts_with('-') && arg.len() > 1) {
+            match arg.as_str() {
+                "-L" => physical = false,
+                "-P" => physical = true,
+                "--" => break,
+                _ => {
+                    writeln!(err, "cd: {}: invalid option", arg)?;
+                    return Ok(2);
+                }
+            }
+        }
+        let args: Vec<&String> = args.collect();
+        if args.len() > 1 {
+            writeln!(err, "cd: too many arguments")?;
+            return Ok(1);
+        }
+        let (dir, mut print) = match args.first().map(|arg| arg.as_str()) {
+            None => match self.get_var("HOME") {
+                Some(home) => (home, false),
+                None => {
+                    writeln!(err, "cd: HOME not set")?;
+                    return Ok(1);
+                }
+            },
+            Some("-") => match self.get_var("OLDPWD") {
+                Some(old) => (old, true),
+                None => {
+                    writeln!(err, "cd: OLDPWD not set")?;
+                    return Ok(1);
+                }
+            },
+            Some(dir) => (String::from(dir), false),
+        };
+        let mut target = self.cur_dir.join(&dir);
+        // Directories that are not explicitly relative to `.` are searched for in
+        // every directory of `CDPATH`, an empty entry standing for `.`.
+        let explicit = dir.starts_with('/') || dir == "." || dir == ".." || dir.starts_with("./");
+        if !explicit && !dir.starts_with("../") {
+            let cdpath = self.get_var("CDPATH").unwrap_or_default();
+            for entry in cdpath.split(':').filter(|_| !cdpath.is_empty()) {
+                let candidate = self.cur_dir.join(entry).join(&dir);
+                if candidate.is_dir() {
+                    print |= !entry.is_empty();
+                    target = candidate;
+                    break;
+                }
+            }
+        }
+        let target = match physical {
+            true => fs::canonicalize(&target),
+            false => Ok(normalize(&target)),
+        };
+        let target = match target {
+            Ok(target) if target.is_dir() => target,
+            Ok(target) if target.exists() => {
+                writeln!(err, "cd: {}: Not a directory", dir)?;
+                return Ok(1);
+            }
+            Ok(_) => {
+                writeln!(err, "cd: {}: No such file or directory", dir)?;
+                return Ok(1);
+            }
+            Err(e) => {
+                writeln!(err, "cd: {}: {}", dir, e)?;
+                return Ok(1);
+            }
+        };
+        let old = self.cur_dir.to_string_lossy().into_owned();
+        let new = target.to_string_lossy().into_owned();
+        if print {
+            writeln!(out, "{}", new)?;
+        }
+        self.set_var("OLDPWD", old);
+        self.set_var("PWD", new);
+        self.cur_dir = target.into();
+        Ok(0)
+    }
+
+    /// `exit [N]`, stopping the program with status `N` or the last status. In a
+    /// subshell only the subshell is stopped.
+    fn exit(&mut self, args: &[String], err: &mut dyn Write) -> io::Result<i32> {
+        let status = match args.first() {
+            Some(code) => match code.parse::<i32>() {
+                Ok(code) => code & 0xff,
+                Err(_) => {
+                    writeln!(err, "exit: {}: numeric argument required", code)?;
+                    2
+                }
+            },
+            None => self.last_status,
+        };
+        self.flow = Some(Flow::Exit(status));
+        Ok(status)
+    }
+
+    /// `test EXPR` and `[ EXPR ]`, succeeding when the expression is true.
+    fn test_builtin(
+        &self,
+        name: &str,
+        args: &[String],
+        io: &Io,
+        err: &mut dyn Write,
+    ) -> io::Result<i32> {
+        let args = match (name, args.split_last()) {
+            ("[", Some((last, args))) if last == "]" => args,
+            ("[", _) => {
+                writeln!(err, "[: missing `]'")?;
+                return Ok(2);
+            }
+            _ => args,
+        };
+        match self.test(args, io) {
+            Ok(value) => Ok(!value as i32),
+            Err(e) => {
+                writeln!(err, "{}: {}", name, e)?;
+                Ok(2)
+            }
+        }
+    }
+
+    /// `local [NAME[=value]...]`, giving variables a value that only lasts until
+    /// the function being run returns. A name without a value starts out unset.
+    fn local(&mut self, args: &[String], err: &mut dyn Write) -> io::Result<i32> {
+        if self.locals.is_empty() {
+            writeln!(err, "local: can only be used in a function")?;
+            return Ok(1);
+        }
+        let mut status = 0;
+        for arg in args {
+            let (name, value) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (arg.as_str(), None),
+            };
+            if !is_name(name) {
+                writeln!(err, "local: `{}': not a valid identifier", arg)?;
+                status = 1;
+                continue;
+            }
+            self.make_local(name);
+            match value {
+                Some(value) => self.set_var(name, String::from(value)),
+                None => {
+                    self.vars.remove(name);
+                }
+            }
+        }
+        Ok(status)
+    }
+
+    /// `return [N]`, leaving the function being run with status `N` or the last
+    /// status.
+    fn ret(&mut self, args: &[String], err: &mut dyn Write) -> io::Result<i32> {
+        let status = match args.first() {
+            Some(code) => match code.parse::<i32>() {
+                Ok(code) => code & 0xff,
+                Err(_) => {
+                    writeln!(err, "return: {}: numeric argument required", code)?;
+                    2
+                }
+            },
+            None => self.last_status,
+        };
+        if self.locals.is_empty() {
+            writeln!(err, "return: can only `return' from a function")?;
+            return Ok(1);
+        }
+        self.flow = Some(Flow::Return(status));
+        Ok(status)
+    }
+
+    /// `break [N]` and `continue [N]`, leaving or going on with the Nth
+    /// innermost loop.
+    fn jump(&mut self, name: &str, args: &[String], err: &mut dyn Write) -> io::Result<i32> {
+        let levels = match args.first().map(|n| n.parse::<usize>()) {
+            None => 1,
+            Some(Ok(n)) if n > 0 => n,
+            Some(_) => {
+                writeln!(err, "{}: {}: loop count out of range", name, args[0])?;
+                return Ok(1);
+            }
+        };
+        if self.loop_depth == 0 {
+            writeln!(
+                err,
+                "{}: only meaningful in a `for', `while', or `until' loop",
+                name
+            )?;
+            return Ok(0);
+        }
+        // Jumping further out than the outermost loop stops at that loop.
+        let levels = levels.min(self.loop_depth);
+        self.flow = Some(match name {
+            "break" => Flow::Break(levels),
+            _ => Flow::Continue(levels),
+        });
+        Ok(0)
+    }
+
+    /// `env` without arguments, printing the environment commands are started with.
+    fn env(&self, out: &mut dyn Write) -> io::Result<i32> {
+        for (name, value) in self.environment() {
+            writeln!(out, "{}={}", name, value)?;
+        }
+        Ok(0)
+    }
+}
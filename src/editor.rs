@@ -0,0 +1,790 @@
+use std::io::{self, Write};
+
+/// The terminal the prompt is read from.
+const INPUT: i32 = libc::STDIN_FILENO;
+
+/// How long to wait for the rest of an escape sequence after `Esc`, so that a
+/// lone `Esc` can leave the insert mode of vi.
+const ESCAPE_TIMEOUT_MS: i32 = 50;
+
+/// Reads one byte from `fd`, or `None` at the end of the input.
+fn read_byte(fd: i32) -> io::Result<Option<u8>> {
+    let mut byte = 0u8;
+    loop {
+        // SAFETY: `byte` outlives the call and has room for one byte.
+        match unsafe { libc::read(fd, (&mut byte as *mut u8).cast(), 1) } {
+            1 => return Ok(Some(byte)),
+            0 => return Ok(None),
+            _ => {
+                let error = io::Error::last_os_error();
+                if error.kind() != io::ErrorKind::Interrupted {
+                    return Err(error);
+                }
+            }
+        }
+    }
+}
+
+/// Whether a byte can be read from `fd` within `timeout` milliseconds.
+fn byte_ready(fd: i32, timeout: i32) -> bool {
+    let mut poll = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // SAFETY: `poll` outlives the call.
+    unsafe { libc::poll(&mut poll, 1, timeout) > 0 }
+}
+
+/// Puts the terminal on `fd` in raw mode until it is dropped, so that keys are
+/// read one at a time without being echoed and Ctrl-C and Ctrl-Z are read as
+/// keys instead of sending signals.
+struct RawMode {
+    fd: i32,
+    saved: libc::termios,
+}
+
+impl RawMode {
+    fn enable(fd: i32) -> io::Result<Self> {
+        // SAFETY: `saved` is filled in by `tcgetattr` before it is used.
+        unsafe {
+            let mut saved: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(fd, &mut saved) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut raw = saved;
+            raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG | libc::IEXTEN);
+            raw.c_iflag &= !(libc::IXON | libc::ICRNL | libc::INLCR);
+            raw.c_cc[libc::VMIN] = 1;
+            raw.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(fd, libc::TCSADRAIN, &raw) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self { fd, saved })
+        }
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        // SAFETY: `saved` holds the settings read by `tcgetattr`.
+        unsafe { libc::tcsetattr(self.fd, libc::TCSADRAIN, &self.saved) };
+    }
+}
+
+/// A key read from the terminal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Char(char),
+    /// A letter typed with Ctrl held down, as in `Ctrl('a')`.
+    Ctrl(char),
+    /// A key typed with Alt held down, or after `Esc`.
+    Alt(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    WordLeft,
+    WordRight,
+    Escape,
+    Other,
+}
+
+/// The keys typed on the terminal.
+struct Input {
+    fd: i32,
+    /// A byte read after `Esc` that starts the next key instead.
+    pushed: Option<u8>,
+}
+
+impl Input {
+    fn byte(&mut self) -> io::Result<Option<u8>> {
+        match self.pushed.take() {
+            Some(byte) => Ok(Some(byte)),
+            None => read_byte(self.fd),
+        }
+    }
+
+    /// Reads the next key, decoding escape sequences and UTF-8. `None` at the
+    /// end of the input.
+    fn key(&mut self) -> io::Result<Option<Key>> {
+        let Some(byte) = self.byte()? else {
+            return Ok(None);
+        };
+        Ok(Some(match byte {
+            b'\r' | b'\n' => Key::Enter,
+            0x7f | 0x08 => Key::Backspace,
+            0x1b if !byte_ready(self.fd, ESCAPE_TIMEOUT_MS) => Key::Escape,
+            0x1b => match self.byte()? {
+                Some(b'[') => self.csi()?,
+                Some(b'O') => match self.byte()? {
+                    Some(b'A') => Key::Up,
+                    Some(b'B') => Key::Down,
+                    Some(b'C') => Key::Right,
+                    Some(b'D') => Key::Left,
+                    Some(b'H') => Key::Home,
+                    Some(b'F') => Key::End,
+                    _ => Key::Other,
+                },
+                Some(0x7f | 0x08) => Key::Alt('\x7f'),
+                Some(byte @ 0x20..=0x7e) => Key::Alt(byte as char),
+                next => {
+                    self.pushed = next;
+                    Key::Escape
+                }
+            },
+            1..=26 => Key::Ctrl((b'a' + byte - 1) as char),
+            0..=31 => Key::Other,
+            _ if byte.is_ascii() => Key::Char(byte as char),
+            _ => Key::Char(self.utf8(byte)?),
+        }))
+    }
+
+    /// Reads the rest of a `CSI` sequence, which the arrow keys and their kin
+    /// send as `Esc [`, parameters and a final letter.
+    fn csi(&mut self) -> io::Result<Key> {
+        let mut params = String::new();
+        let last = loop {
+            match self.byte()? {
+                Some(byte @ 0x40..=0x7e) => break byte,
+                Some(byte) => params.push(byte as char),
+                None => return Ok(Key::Other),
+            }
+        };
+        // Arrows held with Ctrl or Alt, as in `Esc [ 1 ; 5 D`, move over words.
+        let modified = params.ends_with(";5") || params.ends_with(";3");
+        Ok(match (last, params.as_str()) {
+            (b'A', _) => Key::Up,
+            (b'B', _) => Key::Down,
+            (b'C', _) if modified => Key::WordRight,
+            (b'D', _) if modified => Key::WordLeft,
+            (b'C', _) => Key::Right,
+            (b'D', _) => Key::Left,
+            (b'H', _) | (b'~', "1" | "7") => Key::Home,
+            (b'F', _) | (b'~', "4" | "8") => Key::End,
+            (b'~', "3") => Key::Delete,
+            _ => Key::Other,
+        })
+    }
+
+    /// Reads the continuation bytes of a UTF-8 character starting with
+    /// `first`. Invalid sequences come out as the replacement character.
+    fn utf8(&mut self, first: u8) -> io::Result<char> {
+        let len = match first {
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => return Ok(char::REPLACEMENT_CHARACTER),
+        };
+        let mut bytes = vec![first];
+        while bytes.len() < len {
+            match self.byte()? {
+                Some(byte) => bytes.push(byte),
+                None => break,
+            }
+        }
+        Ok(std::str::from_utf8(&bytes)
+            .ok()
+            .and_then(|s| s.chars().next())
+            .unwrap_or(char::REPLACEMENT_CHARACTER))
+    }
+}
+
+/// Whether `c` is part of a word for the Emacs word motions.
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric()
+}
+
+/// The kind of character vi words are made of: blanks, letters, digits and
+/// `_`, or other characters.
+fn vi_class(c: char) -> u8 {
+    match c {
+        _ if c.is_whitespace() => 0,
+        _ if c.is_alphanumeric() || c == '_' => 1,
+        _ => 2,
+    }
+}
+
+/// How reading a line ended.
+enum Done {
+    Line(String),
+    /// Ctrl-C, dropping the line.
+    Cancel,
+    /// Ctrl-D on an empty line, or the end of the input.
+    Eof,
+}
+
+/// Where Ctrl-R is at in searching back through the history.
+struct Search {
+    query: String,
+    /// The entry of the history that matched, with the position of the match
+    /// in characters.
+    found: Option<(usize, usize)>,
+    failed: bool,
+}
+
+/// Reads the lines of an interactive shell, with the cursor movement, kill and
+/// yank and history of Emacs or vi keys.
+#[derive(Debug, Default)]
+pub struct Editor {
+    /// The lines entered so far, oldest first.
+    history: Vec<String>,
+    /// The text killed last, put back by Ctrl-Y or vi `p`.
+    killed: Vec<char>,
+}
+
+impl Editor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a line to the history, unless it is blank or the same as the line
+    /// before it.
+    pub fn add_history(&mut self, line: &str) {
+        let line = line.trim_end_matches('\n');
+        if !line.trim().is_empty() && self.history.last().is_none_or(|last| last != line) {
+            self.history.push(String::from(line));
+        }
+    }
+
+    /// Shows `prompt` and reads a line, with vi keys when `vi` is set. The line
+    /// comes with its newline, and is empty after Ctrl-C. `None` at the end of
+    /// the input. When stdin is not a terminal the line is read as it is.
+    pub fn read_line(&mut self, prompt: &str, vi: bool) -> io::Result<Option<String>> {
+        let mut out = io::stdout();
+        write!(out, "{}", prompt)?;
+        out.flush()?;
+        let Ok(raw) = RawMode::enable(INPUT) else {
+            return read_plain(INPUT);
+        };
+        let done = Edit::new(self, prompt, vi).run();
+        drop(raw);
+        match done? {
+            Done::Line(line) => Ok(Some(line + "\n")),
+            Done::Cancel => Ok(Some(String::new())),
+            Done::Eof => Ok(None),
+        }
+    }
+}
+
+/// Reads a line from `fd` without editing it, one byte at a time so that
+/// nothing after it is taken from the commands that read the rest.
+fn read_plain(fd: i32) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    while let Some(byte) = read_byte(fd)? {
+        line.push(byte);
+        if byte == b'\n' {
+            break;
+        }
+    }
+    Ok((!line.is_empty()).then(|| String::from_utf8_lossy(&line).into_owned()))
+}
+
+/// A line being edited.
+struct Edit<'a> {
+    editor: &'a mut Editor,
+    input: Input,
+    prompt: &'a str,
+    chars: Vec<char>,
+    /// The position of the cursor in `chars`.
+    cursor: usize,
+    /// The entry of the history being edited, the length of the history for
+    /// the new line.
+    index: usize,
+    /// The new line, kept while going through the history.
+    draft: Vec<char>,
+    search: Option<Search>,
+    vi: bool,
+    /// Whether vi keys are in command mode instead of insert mode.
+    command: bool,
+    /// A vi operator such as `d` waiting for its motion, or `r` for its
+    /// character.
+    pending: Option<char>,
+    /// Whether the last key killed text, so that the next kill adds to it.
+    killing: bool,
+    /// Whether the key being handled killed text.
+    killed: bool,
+}
+
+impl<'a> Edit<'a> {
+    fn new(editor: &'a mut Editor, prompt: &'a str, vi: bool) -> Self {
+        let index = editor.history.len();
+        Self {
+            editor,
+            input: Input {
+                fd: INPUT,
+                pushed: None,
+            },
+            prompt,
+            chars: Vec::new(),
+            cursor: 0,
+            index,
+            draft: Vec::new(),
+            search: None,
+            vi,
+            command: false,
+            pending: None,
+            killing: false,
+            killed: false,
+        }
+    }
+
+    /// Handles keys until the line is entered.
+    fn run(&mut self) -> io::Result<Done> {
+        loop {
+            let Some(key) = self.input.key()? else {
+                write!(io::stdout(), "\r\n")?;
+                return Ok(Done::Eof);
+            };
+            let done = match self.search {
+                Some(_) => self.search_key(key),
+                None if self.vi && self.command => self.vi_key(key),
+                None if self.vi && key == Key::Escape => {
+                    self.command = true;
+                    self.cursor = self.cursor.saturating_sub(1);
+                    None
+                }
+                // A key typed right after `Esc` comes in with it, as if Alt was held.
+                None if self.vi => match key {
+                    Key::Alt(c) => {
+                        self.command = true;
+                        self.cursor = self.cursor.saturating_sub(1);
+                        self.vi_key(Key::Char(c))
+                    }
+                    key => self.emacs_key(key),
+                },
+                None => self.emacs_key(key),
+            };
+            self.killing = std::mem::take(&mut self.killed);
+            if let Some(done) = done {
+                let mut out = io::stdout();
+                match &done {
+                    Done::Cancel => write!(out, "^C\r\n")?,
+                    _ => write!(out, "\r\n")?,
+                }
+                out.flush()?;
+                return Ok(done);
+            }
+            self.refresh()?;
+        }
+    }
+
+    /// Draws the line again over the old one and puts the cursor back.
+    fn refresh(&self) -> io::Result<()> {
+        let prompt = match &self.search {
+            Some(search) => {
+                let failed = if search.failed { "failed " } else { "" };
+                format!("({}reverse-i-search)`{}': ", failed, search.query)
+            }
+            None => String::from(self.prompt),
+        };
+        let mut out = io::stdout().lock();
+        write!(out, "\r{}{}\x1b[K", prompt, self.line())?;
+        let after = self.chars.len() - self.cursor;
+        if after > 0 {
+            write!(out, "\x1b[{}D", after)?;
+        }
+        out.flush()
+    }
+
+    fn line(&self) -> String {
+        self.chars.iter().collect()
+    }
+
+    /// Replaces the line with `text`, with the cursor at the end.
+    fn load(&mut self, text: Vec<char>) {
+        self.chars = text;
+        self.cursor = self.chars.len();
+    }
+
+    /// Removes the characters from `start` to `end`, keeping them for yanking
+    /// when `kill` is set. Kills in a row add up to one text, except in the
+    /// command mode of vi.
+    fn delete(&mut self, start: usize, end: usize, kill: bool) {
+        let (start, end) = (start.min(end), start.max(end).min(self.chars.len()));
+        let mut removed: Vec<char> = self.chars.drain(start..end).collect();
+        if kill && !removed.is_empty() {
+            if self.killing && !self.command {
+                let killed = std::mem::take(&mut self.editor.killed);
+                if end <= self.cursor {
+                    removed.extend(killed);
+                } else {
+                    removed.splice(0..0, killed);
+                }
+            }
+            self.editor.killed = removed;
+            self.killed = true;
+        }
+        self.cursor = start;
+    }
+
+    /// Inserts the text killed last before the cursor, leaving the cursor
+    /// after it.
+    fn yank(&mut self) {
+        let killed = self.editor.killed.clone();
+        let len = killed.len();
+        self.chars.splice(self.cursor..self.cursor, killed);
+        self.cursor += len;
+    }
+
+    /// Goes to the entry `index` of the history, or to the new line past the
+    /// end of it.
+    fn recall(&mut self, index: usize) {
+        if index > self.editor.history.len() || index == self.index {
+            return;
+        }
+        if self.index == self.editor.history.len() {
+            self.draft = self.chars.clone();
+        }
+        self.index = index;
+        let text = match self.editor.history.get(index) {
+            Some(entry) => entry.chars().collect(),
+            None => self.draft.clone(),
+        };
+        self.load(text);
+    }
+
+    /// The start of the word before the cursor, for Emacs keys.
+    fn word_left(&self) -> usize {
+        let mut i = self.cursor;
+        while i > 0 && !is_word(self.chars[i - 1]) {
+            i -= 1;
+        }
+        while i > 0 && is_word(self.chars[i - 1]) {
+            i -= 1;
+        }
+        i
+    }
+
+    /// The end of the word after the cursor, for Emacs keys.
+    fn word_right(&self) -> usize {
+        let mut i = self.cursor;
+        while i < self.chars.len() && !is_word(self.chars[i]) {
+            i += 1;
+        }
+        while i < self.chars.len() && is_word(self.chars[i]) {
+            i += 1;
+        }
+        i
+    }
+
+    /// Handles a key of the Emacs keymap, which the insert mode of vi shares.
+    fn emacs_key(&mut self, key: Key) -> Option<Done> {
+        let len = self.chars.len();
+        match key {
+            Key::Char(c) => {
+                self.chars.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            Key::Enter => return Some(Done::Line(self.line())),
+            Key::Ctrl('c') => return Some(Done::Cancel),
+            Key::Ctrl('d') if len == 0 => return Some(Done::Eof),
+            Key::Ctrl('d') | Key::Delete => self.delete(self.cursor, self.cursor + 1, false),
+            Key::Backspace if self.cursor > 0 => self.delete(self.cursor - 1, self.cursor, false),
+            Key::Ctrl('a') | Key::Home => self.cursor = 0,
+            Key::Ctrl('e') | Key::End => self.cursor = len,
+            Key::Ctrl('b') | Key::Left => self.cursor = self.cursor.saturating_sub(1),
+            Key::Ctrl('f') | Key::Right => self.cursor = (self.cursor + 1).min(len),
+            Key::Alt('b') | Key::WordLeft => self.cursor = self.word_left(),
+            Key::Alt('f') | Key::WordRight => self.cursor = self.word_right(),
+            Key::Ctrl('k') => self.delete(self.cursor, len, true),
+            Key::Ctrl('u') => self.delete(0, self.cursor, true),
+            Key::Ctrl('w') => {
+                // Words are only separated by blanks here, as in `unix-word-rubout`.
+                let mut start = self.cursor;
+                while start > 0 && self.chars[start - 1].is_whitespace() {
+                    start -= 1;
+                }
+                while start > 0 && !self.chars[start - 1].is_whitespace() {
+                    start -= 1;
+                }
+                self.delete(start, self.cursor, true);
+            }
+            Key::Alt('\x7f') => self.delete(self.word_left(), self.cursor, true),
+            Key::Alt('d') => self.delete(self.cursor, self.word_right(), true),
+            Key::Ctrl('y') => self.yank(),
+            Key::Ctrl('t') if self.cursor > 0 && len > 1 => {
+                // Swaps the characters around the cursor, or the last two at the end.
+                let at = self.cursor.min(len - 1);
+                self.chars.swap(at - 1, at);
+                self.cursor = at + 1;
+            }
+            Key::Ctrl('p') | Key::Up => self.recall(self.index.wrapping_sub(1)),
+            Key::Ctrl('n') | Key::Down => self.recall(self.index + 1),
+            Key::Ctrl('r') => self.start_search(),
+            Key::Ctrl('l') => {
+                let _ = write!(io::stdout(), "\x1b[H\x1b[2J");
+            }
+            _ => {}
+        }
+        None
+    }
+
+    /// Handles a key of the command mode of vi.
+    fn vi_key(&mut self, key: Key) -> Option<Done> {
+        let len = self.chars.len();
+        if let Some(operator) = self.pending.take() {
+            self.vi_operator(operator, key);
+            return None;
+        }
+        match key {
+            Key::Enter => return Some(Done::Line(self.line())),
+            Key::Ctrl('c') => return Some(Done::Cancel),
+            Key::Ctrl('d') if len == 0 => return Some(Done::Eof),
+            Key::Ctrl('r') => self.start_search(),
+            Key::Char('i') => self.command = false,
+            Key::Char('a') => {
+                self.cursor = (self.cursor + 1).min(len);
+                self.command = false;
+            }
+            Key::Char('I') => {
+                self.cursor = self.first_non_blank();
+                self.command = false;
+            }
+            Key::Char('A') => {
+                self.cursor = len;
+                self.command = false;
+            }
+            Key::Char('x') | Key::Delete => self.delete(self.cursor, self.cursor + 1, true),
+            Key::Char('X') if self.cursor > 0 => self.delete(self.cursor - 1, self.cursor, true),
+            Key::Char('D') => self.delete(self.cursor, len, true),
+            Key::Char('C') => {
+                self.delete(self.cursor, len, true);
+                self.command = false;
+            }
+            Key::Char('S') => {
+                self.delete(0, len, true);
+                self.command = false;
+            }
+            Key::Char('s') => {
+                self.delete(self.cursor, self.cursor + 1, true);
+                self.command = false;
+            }
+            Key::Char('p') => {
+                self.cursor = (self.cursor + 1).min(len);
+                self.yank();
+                self.cursor = self.cursor.saturating_sub(1);
+            }
+            Key::Char('P') => {
+                self.yank();
+                self.cursor = self.cursor.saturating_sub(1);
+            }
+            Key::Char(operator @ ('d' | 'c' | 'y' | 'r')) => self.pending = Some(operator),
+            Key::Char('k' | '-') | Key::Up | Key::Ctrl('p') => {
+                self.recall(self.index.wrapping_sub(1));
+                self.cursor = 0;
+            }
+            Key::Char('j' | '+') | Key::Down | Key::Ctrl('n') => {
+                self.recall(self.index + 1);
+                self.cursor = 0;
+            }
+            key => match self.vi_motion(key) {
+                Some(target) => self.cursor = target,
+                None => return None,
+            },
+        }
+        // In command mode the cursor stays on a character.
+        if self.command {
+            self.cursor = self.cursor.min(self.chars.len().saturating_sub(1));
+        }
+        None
+    }
+
+    /// Where a vi motion key moves the cursor to, `None` for other keys.
+    fn vi_motion(&self, key: Key) -> Option<usize> {
+        let len = self.chars.len();
+        let mut i = self.cursor;
+        Some(match key {
+            Key::Char('h') | Key::Left | Key::Backspace => i.saturating_sub(1),
+            Key::Char('l' | ' ') | Key::Right => (i + 1).min(len),
+            Key::Char('0') | Key::Home => 0,
+            Key::Char('^') => self.first_non_blank(),
+            Key::Char('$') | Key::End => len,
+            Key::Char('w') | Key::WordRight => {
+                if i < len {
+                    let class = vi_class(self.chars[i]);
+                    while i < len && class != 0 && vi_class(self.chars[i]) == class {
+                        i += 1;
+                    }
+                }
+                while i < len && vi_class(self.chars[i]) == 0 {
+                    i += 1;
+                }
+                i
+            }
+            Key::Char('b') | Key::WordLeft => {
+                while i > 0 && vi_class(self.chars[i - 1]) == 0 {
+                    i -= 1;
+                }
+                if i > 0 {
+                    let class = vi_class(self.chars[i - 1]);
+                    while i > 0 && vi_class(self.chars[i - 1]) == class {
+                        i -= 1;
+                    }
+                }
+                i
+            }
+            Key::Char('e') => {
+                i += 1;
+                while i < len && vi_class(self.chars[i]) == 0 {
+                    i += 1;
+                }
+                if i < len {
+                    let class = vi_class(self.chars[i]);
+                    while i + 1 < len && vi_class(self.chars[i + 1]) == class {
+                        i += 1;
+                    }
+                }
+                i.min(len.saturating_sub(1))
+            }
+            _ => return None,
+        })
+    }
+
+    fn first_non_blank(&self) -> usize {
+        self.chars
+            .iter()
+            .position(|c| !c.is_whitespace())
+            .unwrap_or(self.chars.len())
+    }
+
+    /// Applies the vi operator `d`, `c` or `y` up to where `key` moves, or to
+    /// the whole line when `key` repeats the operator. `r` replaces the
+    /// character under the cursor with `key`.
+    fn vi_operator(&mut self, operator: char, key: Key) {
+        let len = self.chars.len();
+        if operator == 'r' {
+            if let (Key::Char(c), Some(slot)) = (key, self.chars.get_mut(self.cursor)) {
+                *slot = c;
+            }
+            return;
+        }
+        let (start, end) = match key {
+            Key::Char(c) if c == operator => (0, len),
+            // `e` moves onto the last character of a word, which is included.
+            Key::Char('e') => match self.vi_motion(key) {
+                Some(target) => (self.cursor, (target + 1).min(len)),
+                None => return,
+            },
+            key => match self.vi_motion(key) {
+                Some(target) => (self.cursor.min(target), self.cursor.max(target)),
+                None => return,
+            },
+        };
+        // Like vi, `cw` only changes up to the end of the word.
+        let end = match (operator, key) {
+            ('c', Key::Char('w')) => {
+                let mut end = end;
+                while end > start && self.chars[end - 1].is_whitespace() {
+                    end -= 1;
+                }
+                end
+            }
+            _ => end,
+        };
+        if operator == 'y' {
+            self.editor.killed = self.chars[start..end].to_vec();
+            self.cursor = start.min(len.saturating_sub(1));
+            return;
+        }
+        self.delete(start, end, true);
+        match operator {
+            'c' => self.command = false,
+            _ => self.cursor = self.cursor.min(self.chars.len().saturating_sub(1)),
+        }
+    }
+
+    fn start_search(&mut self) {
+        if self.index == self.editor.history.len() {
+            self.draft = self.chars.clone();
+        }
+        self.search = Some(Search {
+            query: String::new(),
+            found: None,
+            failed: false,
+        });
+    }
+
+    /// Looks for the query of the search in the history, at entry `from` and
+    /// the ones before it, and shows the latest entry holding it.
+    fn find(&mut self, from: usize) {
+        let Some(search) = &mut self.search else {
+            return;
+        };
+        let found = self.editor.history[..from.min(self.editor.history.len())]
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(index, entry)| {
+                let at = entry.find(&search.query)?;
+                Some((index, entry[..at].chars().count()))
+            });
+        search.failed = found.is_none();
+        if let Some((index, at)) = found {
+            search.found = found;
+            self.chars = self.editor.history[index].chars().collect();
+            self.cursor = at;
+        }
+    }
+
+    /// Handles a key while searching back through the history with Ctrl-R.
+    /// Keys that do not edit the query end the search, keeping the entry found,
+    /// and are then handled as usual.
+    fn search_key(&mut self, key: Key) -> Option<Done> {
+        let Some(search) = &mut self.search else {
+            return None;
+        };
+        // The entry found is searched again as the query grows.
+        let current = search
+            .found
+            .map_or(self.editor.history.len(), |(i, _)| i + 1);
+        match key {
+            Key::Char(c) => {
+                search.query.push(c);
+                self.find(current);
+            }
+            Key::Backspace => {
+                search.query.pop();
+                self.find(self.editor.history.len());
+            }
+            Key::Ctrl('r') => {
+                let before = search.found.map_or(self.editor.history.len(), |(i, _)| i);
+                self.find(before);
+            }
+            Key::Ctrl('g') | Key::Ctrl('c') => {
+                self.search = None;
+                self.recall_current();
+            }
+            key => {
+                let found = self.search.take().and_then(|search| search.found);
+                if let Some((index, _)) = found {
+                    self.index = index;
+                }
+                return match (self.vi, key) {
+                    (_, Key::Enter) => Some(Done::Line(self.line())),
+                    (true, Key::Escape) => {
+                        self.command = true;
+                        None
+                    }
+                    (true, key) if self.command => self.vi_key(key),
+                    (_, key) => self.emacs_key(key),
+                };
+            }
+        }
+        None
+    }
+
+    /// Shows the line the search started from again after it was cancelled.
+    fn recall_current(&mut self) {
+        let text = match self.editor.history.get(self.index) {
+            Some(entry) => entry.chars().collect(),
+            None => self.draft.clone(),
+        };
+        self.load(text);
+    }
+}
@@ -1,9 +1,322 @@
-use core::panic;
+use std::fmt;
+use std::io;
 
-pub fn error(line: usize, message: String) {
-    report(line, String::new(), message);
+/// A range of bytes in the source, along with the line it starts on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
 }
-fn report(line: usize, loc: String, message: String) {
-    eprintln!("[line {line}] Error {loc}: {message}");
-    panic!();
+
+impl Span {
+    /// The span running from the start of `self` to the end of `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            end: other.end.max(self.end),
+            ..self
+        }
+    }
+}
+
+/// The source line an error points into, rendered with the offending part
+/// underlined.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snippet {
+    pub line: usize,
+    /// Column of the first underlined character, starting at 1.
+    pub column: usize,
+    pub text: String,
+    /// Number of characters to underline, at least 1.
+    pub width: usize,
+}
+
+impl Snippet {
+    /// Cuts the line holding `span` out of `source`. A span running over several
+    /// lines is only underlined up to the end of the first one.
+    pub fn new(source: &str, span: Span) -> Self {
+        let start = span.start.min(source.len());
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start..]
+            .find('\n')
+            .map_or(source.len(), |i| start + i);
+        let end = span.end.clamp(start, line_end);
+        Self {
+            line: span.line,
+            column: source[line_start..start].chars().count() + 1,
+            text: String::from(&source[line_start..line_end]),
+            width: source[start..end].chars().count().max(1),
+        }
+    }
+}
+
+impl fmt::Display for Snippet {
+    /// Renders the line like rustc does, with a `^~~~` underline below it.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let gutter = " ".repeat(self.line.to_string().len());
+        // Tabs are kept in the padding so the underline lines up with the text.
+        let pad: String = self
+            .text
+            .chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        writeln!(
+            f,
+            "{}--> line {}, column {}",
+            gutter, self.line, self.column
+        )?;
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", self.line, self.text)?;
+        write!(f, "{} | {}^{}", gutter, pad, "~".repeat(self.width - 1))
+    }
+}
+
+/// Everything that can go wrong while scanning, parsing or running a program.
+#[derive(Debug)]
+pub enum ShellError {
+    /// The source could not be split into tokens, e.g. an unterminated quote.
+    Lex {
+        message: String,
+        snippet: Snippet,
+        hint: Option<String>,
+    },
+    /// The tokens do not make up a valid program, e.g. a stray `|`.
+    Parse {
+        message: String,
+        snippet: Snippet,
+        hint: Option<String>,
+    },
+    /// A word could not be expanded, e.g. `${NAME?}` while `NAME` is unset.
+    Expansion {
+        message: String,
+        /// The command the word belongs to, once it is known.
+        snippet: Option<Snippet>,
+    },
+    /// A command could not be started.
+    Exec { command: String, error: io::Error },
+    /// Opening, reading or writing a file, pipe or descriptor failed.
+    Io { context: String, error: io::Error },
+    /// A `[[ ]]` condition could not be evaluated, e.g. `[[ a =~ ( ]]`.
+    Test { message: String },
+    /// A function call went deeper than `FUNCNEST` calls.
+    Nesting { function: String, limit: usize },
+}
+
+impl ShellError {
+    pub fn lex(source: &str, span: Span, message: impl Into<String>) -> Self {
+        Self::Lex {
+            message: message.into(),
+            snippet: Snippet::new(source, span),
+            hint: None,
+        }
+    }
+    pub fn parse(source: &str, span: Span, message: impl Into<String>) -> Self {
+        Self::Parse {
+            message: message.into(),
+            snippet: Snippet::new(source, span),
+            hint: None,
+        }
+    }
+    pub fn expansion(message: impl Into<String>) -> Self {
+        Self::Expansion {
+            message: message.into(),
+            snippet: None,
+        }
+    }
+    pub fn io(context: impl Into<String>, error: io::Error) -> Self {
+        Self::Io {
+            context: context.into(),
+            error,
+        }
+    }
+
+    /// Adds a hint on how to fix a syntax error.
+    pub fn with_hint(mut self, text: impl Into<String>) -> Self {
+        if let Self::Lex { hint, .. } | Self::Parse { hint, .. } = &mut self {
+            *hint = Some(text.into());
+        }
+        self
+    }
+
+    /// Points an expansion error at the part of `source` it happened in, unless
+    /// it already points somewhere.
+    pub fn located(mut self, source: &str, span: Span) -> Self {
+        if let Self::Expansion { snippet, .. } = &mut self {
+            snippet.get_or_insert_with(|| Snippet::new(source, span));
+        }
+        self
+    }
+
+    /// The exit status of a command that failed with this error: `2` for syntax
+    /// errors, `127` for commands that cannot be found and `126` for commands
+    /// that cannot be run.
+    pub fn status(&self) -> i32 {
+        match self {
+            Self::Lex { .. } | Self::Parse { .. } | Self::Test { .. } => 2,
+            Self::Exec { error, .. } if error.kind() == io::ErrorKind::NotFound => 127,
+            Self::Exec { .. } => 126,
+            Self::Expansion { .. } | Self::Io { .. } | Self::Nesting { .. } => 1,
+        }
+    }
+
+    /// Whether the error stops the whole program instead of only failing the
+    /// command it happened in. Runaway recursion unwinds every call on the way.
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            Self::Lex { .. } | Self::Parse { .. } | Self::Expansion { .. } | Self::Nesting { .. }
+        )
+    }
+}
+
+impl fmt::Display for ShellError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Lex {
+                message,
+                snippet,
+                hint,
+            }
+            | Self::Parse {
+                message,
+                snippet,
+                hint,
+            } => {
+                write!(f, "error: {}\n{}", message, snippet)?;
+                if let Some(hint) = hint {
+                    let gutter = " ".repeat(snippet.line.to_string().len());
+                    write!(f, "\n{} = hint: {}", gutter, hint)?;
+                }
+                Ok(())
+            }
+            Self::Expansion {
+                message,
+                snippet: Some(snippet),
+            } => write!(f, "error: {}\n{}", message, snippet),
+            Self::Expansion { message, .. } => write!(f, "{}", message),
+            Self::Exec { command, error } if error.kind() == io::ErrorKind::NotFound => {
+                write!(f, "{}: command not found", command)
+            }
+            Self::Exec { command, error } => write!(f, "{}: {}", command, error),
+            Self::Io { context, error } => write!(f, "{}: {}", context, error),
+            Self::Test { message } => write!(f, "[[: {}", message),
+            Self::Nesting { function, limit } => write!(
+                f,
+                "{}: maximum function nesting level exceeded ({})",
+                function, limit
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ShellError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Exec { error, .. } | Self::Io { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_program;
+    use crate::scanner::Scanner;
+
+    /// The error the scanner or parser reports for `source`.
+    fn syntax_error(source: &str) -> ShellError {
+        let mut lexer = Scanner::new(String::from(source));
+        match lexer.scan_tokens() {
+            Ok(()) => parse_program(lexer).expect_err("source should not parse"),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn lex_error_points_at_the_opening_quote() {
+        let error = syntax_error("echo \"héllo wörld");
+        assert!(matches!(error, ShellError::Lex { .. }));
+        assert_eq!(error.status(), 2);
+        assert!(error.is_fatal());
+        assert_eq!(
+            error.to_string(),
+            "error: unterminated string\n \
+             --> line 1, column 6\n  \
+             |\n\
+             1 | echo \"héllo wörld\n  \
+             |      ^\n  \
+             = hint: add a closing \" to end the string"
+        );
+    }
+
+    #[test]
+    fn parse_error_counts_columns_in_characters() {
+        let error = syntax_error("echo héllo | | cat");
+        let ShellError::Parse { snippet, .. } = &error else {
+            panic!("expected a parse error, got {:?}", error);
+        };
+        // `é` takes two bytes but one column.
+        assert_eq!(snippet.column, 14);
+        assert_eq!(
+            error.to_string(),
+            "error: expected a command before \"|\"\n \
+             --> line 1, column 14\n  \
+             |\n\
+             1 | echo héllo | | cat\n  \
+             |              ^\n  \
+             = hint: \"|\" needs a command on both sides"
+        );
+    }
+
+    #[test]
+    fn parse_error_underlines_the_whole_token_on_its_line() {
+        let error = syntax_error("true\nif true; then fi");
+        let ShellError::Parse { snippet, .. } = &error else {
+            panic!("expected a parse error, got {:?}", error);
+        };
+        assert_eq!((snippet.line, snippet.column, snippet.width), (2, 15, 2));
+        assert!(error
+            .to_string()
+            .contains("2 | if true; then fi\n  |               ^~\n"));
+    }
+
+    #[test]
+    fn exec_error_reports_missing_commands() {
+        let missing = ShellError::Exec {
+            command: String::from("nope"),
+            error: io::Error::from(io::ErrorKind::NotFound),
+        };
+        assert_eq!(missing.to_string(), "nope: command not found");
+        assert_eq!(missing.status(), 127);
+        assert!(!missing.is_fatal());
+        let denied = ShellError::Exec {
+            command: String::from("./script"),
+            error: io::Error::from(io::ErrorKind::PermissionDenied),
+        };
+        assert_eq!(denied.status(), 126);
+        assert!(denied.to_string().starts_with("./script: "));
+    }
+
+    #[test]
+    fn expansion_error_is_located_once() {
+        let source = "echo ok; echo ${X?unset}";
+        let span = Span {
+            start: 9,
+            end: source.len(),
+            line: 1,
+        };
+        let error = ShellError::expansion("X: unset")
+            .located(source, span)
+            .located(source, Span::default());
+        assert_eq!(
+            error.to_string(),
+            "error: X: unset\n \
+             --> line 1, column 10\n  \
+             |\n\
+             1 | echo ok; echo ${X?unset}\n  \
+             |          ^~~~~~~~~~~~~~~"
+        );
+    }
 }
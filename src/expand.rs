@@ -0,0 +1,650 @@
+use crate::error::ShellError;
+use crate::glob;
+use crate::interpreter::{ExecContext, Io, STACK_SIZE};
+use crate::parser::parse_program;
+use crate::scanner::Scanner;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{pipe, Read};
+use std::thread;
+
+/// A piece of a word after expansion.
+#[derive(Debug)]
+struct Segment {
+    text: String,
+    /// Whether the text was quoted or escaped in the original word.
+    quoted: bool,
+    /// Whether the text came from an unquoted expansion and is subject to field
+    /// splitting.
+    split: bool,
+    /// Whether the segment ends the current field, as between the parameters of
+    /// `"$@"`.
+    boundary: bool,
+}
+
+/// A word after field splitting, along with the pattern it is globbed with.
+#[derive(Debug, Default)]
+struct Field {
+    text: String,
+    /// The text with every quoted wildcard escaped.
+    pattern: String,
+}
+
+impl Field {
+    fn push(&mut self, text: &str, quoted: bool) {
+        self.text.push_str(text);
+        for c in text.chars() {
+            if quoted && glob::SPECIAL.contains(&c) {
+                self.pattern.push('\\');
+            }
+            self.pattern.push(c);
+        }
+    }
+}
+
+/// Appends `text` to the last segment when it has the same quoting, otherwise
+/// starts a new segment.
+fn push(segs: &mut Vec<Segment>, text: &str, quoted: bool, split: bool) {
+    match segs.last_mut() {
+        Some(last) if last.quoted == quoted && last.split == split && !last.boundary => {
+            last.text.push_str(text)
+        }
+        _ => segs.push(Segment {
+            text: String::from(text),
+            quoted,
+            split,
+            boundary: false,
+        }),
+    }
+}
+
+/// Ends the current field, so whatever is pushed next starts a new one.
+fn push_boundary(segs: &mut Vec<Segment>) {
+    segs.push(Segment {
+        text: String::new(),
+        quoted: false,
+        split: false,
+        boundary: true,
+    });
+}
+
+/// Joins segments back into a single string, the fields of `"$@"` separated
+/// by spaces.
+fn joined(segs: Vec<Segment>) -> String {
+    segs.into_iter()
+        .map(|seg| match seg.boundary {
+            true => String::from(" "),
+            false => seg.text,
+        })
+        .collect()
+}
+
+/// Finds the index of the bracket closing the one at `start`, skipping quoted
+/// text.
+fn closing(chars: &[char], start: usize) -> Option<usize> {
+    let (open, close) = match chars[start] {
+        '{' => ('{', '}'),
+        _ => ('(', ')'),
+    };
+    let mut depth = 0;
+    let mut i = start;
+    while i < chars.len() {
+        match chars[i] {
+            c if c == open => depth += 1,
+            c if c == close => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            '\\' => i += 1,
+            quote @ ('\'' | '"' | '`') => {
+                i += 1;
+                while i < chars.len() && chars[i] != quote {
+                    if quote != '\'' && chars[i] == '\\' {
+                        i += 1;
+                    }
+                    i += 1;
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+fn is_name_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Whether `name` can be used as a variable name.
+pub fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(is_name_start) && chars.all(is_name_char)
+}
+
+/// Characters that name a special parameter on their own, such as `$?` or the
+/// positional parameter `$1`.
+fn is_special(c: char) -> bool {
+    matches!(c, '?' | '$' | '!' | '#' | '@' | '*') || c.is_ascii_digit()
+}
+
+/// Looks up the home directory of `user` in `/etc/passwd`.
+fn home_dir(user: &str) -> Option<String> {
+    let passwd = fs::read_to_string("/etc/passwd").ok()?;
+    passwd.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        match fields.as_slice() {
+            [name, _, _, _, _, home, ..] if *name == user => Some(String::from(*home)),
+            _ => None,
+        }
+    })
+}
+
+impl ExecContext {
+    /// Expands a word into the fields it produces after parameter expansion,
+    /// quote removal, field splitting and pathname expansion. Fields are kept
+    /// as `OsString`s so that file names which are not valid UTF-8 reach
+    /// commands unchanged. Fails when a pattern matched nothing while
+    /// `failglob` is set.
+    pub fn expand_word(&mut self, word: &str) -> Result<Vec<OsString>, ShellError> {
+        let chars: Vec<char> = word.chars().collect();
+        let segs = self.expand_segments(&chars)?;
+        let mut words = Vec::new();
+        for field in self.split_fields(segs) {
+            if self.option("noglob") || !glob::has_glob(&field.pattern) {
+                words.push(field.text.into());
+                continue;
+            }
+            let paths = glob::expand(&field.pattern, &self.cur_dir, self.option("dotglob"));
+            if !paths.is_empty() {
+                words.extend(paths);
+            } else if self.option("failglob") {
+                return Err(ShellError::expansion(format!("no match: {}", field.text)));
+            } else if !self.option("nullglob") {
+                words.push(field.text.into());
+            }
+        }
+        Ok(words)
+    }
+
+    /// Expands a word into a single string without field splitting, as done for
+    /// assignments and redirection targets.
+    pub fn expand_string(&mut self, word: &str) -> Result<String, ShellError> {
+        let chars: Vec<char> = word.chars().collect();
+        Ok(joined(self.expand_segments(&chars)?))
+    }
+
+    /// Expands a word into a pattern without field splitting or pathname
+    /// expansion, with the wildcards that were quoted escaped so they only match
+    /// themselves, as done for the patterns of `case`.
+    pub fn expand_pattern(&mut self, word: &str) -> Result<String, ShellError> {
+        let chars: Vec<char> = word.chars().collect();
+        let mut field = Field::default();
+        for seg in self.expand_segments(&chars)? {
+            match seg.boundary {
+                true => field.push(" ", true),
+                false => field.push(&seg.text, seg.quoted),
+            }
+        }
+        Ok(field.pattern)
+    }
+
+    /// Expands a word into a regex for `=~`, where the parts that were quoted
+    /// match literally.
+    pub fn expand_regex(&mut self, word: &str) -> Result<String, ShellError> {
+        let chars: Vec<char> = word.chars().collect();
+        let mut pattern = String::new();
+        for seg in self.expand_segments(&chars)? {
+            match (seg.boundary, seg.quoted) {
+                (true, _) => pattern.push(' '),
+                (false, true) => pattern += &regex::escape(&seg.text),
+                (false, false) => pattern += &seg.text,
+            }
+        }
+        Ok(pattern)
+    }
+
+    /// Expands the body of a heredoc whose delimiter was not quoted. Parameters,
+    /// command substitutions and arithmetic are expanded as inside double
+    /// quotes, but quotes are kept as they are.
+    pub fn expand_heredoc(&mut self, body: &str) -> Result<String, ShellError> {
+        let chars: Vec<char> = body.chars().collect();
+        let mut text = String::new();
+        let mut i = 0;
+        while i < chars.len() {
+            match chars[i] {
+                '\\' if i + 1 < chars.len() => {
+                    match chars[i + 1] {
+                        '\n' => {}
+                        c @ ('$' | '`' | '\\') => text.push(c),
+                        c => {
+                            text.push('\\');
+                            text.push(c);
+                        }
+                    }
+                    i += 2;
+                }
+                '$' => match self.expand_parameter(&chars, &mut i)? {
+                    Some(value) => text += &value,
+                    None => text.push('$'),
+                },
+                '`' => text += &self.expand_backticks(&chars, &mut i)?,
+                c => {
+                    text.push(c);
+                    i += 1;
+                }
+            }
+        }
+        Ok(text)
+    }
+
+    /// Looks up `name[subscript]`, where the subscript is an arithmetic
+    /// expression, or `@` and `*` for every element joined by spaces. A variable
+    /// that is not an array is its own element 0.
+    fn element(&mut self, name: &str, subscript: &str) -> Result<Option<String>, ShellError> {
+        let values = match self.arrays.get(name) {
+            Some(values) => values.clone(),
+            None => self.get_var(name).into_iter().collect(),
+        };
+        if subscript == "@" || subscript == "*" {
+            return Ok((!values.is_empty()).then(|| values.join(" ")));
+        }
+        let subscript = self.expand_string(subscript)?;
+        let index = self.arithmetic(&subscript)?;
+        Ok(usize::try_from(index)
+            .ok()
+            .and_then(|index| values.get(index).cloned()))
+    }
+
+    fn expand_segments(&mut self, chars: &[char]) -> Result<Vec<Segment>, ShellError> {
+        let mut segs = Vec::new();
+        let mut i = 0;
+        if let Some((home, end)) = self.expand_tilde(chars) {
+            push(&mut segs, &home, true, false);
+            i = end;
+        }
+        while i < chars.len() {
+            match chars[i] {
+                '\'' => {
+                    // Nothing is expanded inside single quotes.
+                    let end = chars[i + 1..]
+                        .iter()
+                        .position(|c| *c == '\'')
+                        .map_or(chars.len(), |end| i + 1 + end);
+                    let text: String = chars[i + 1..end].iter().collect();
+                    push(&mut segs, &text, true, false);
+                    i = end + 1;
+                }
+                '"' => {
+                    i += 1;
+                    self.expand_double_quoted(chars, &mut i, &mut segs)?;
+                }
+                '\\' if i + 1 < chars.len() => {
+                    push(&mut segs, &chars[i + 1].to_string(), true, false);
+                    i += 2;
+                }
+                '$' => match self.expand_parameter(chars, &mut i)? {
+                    Some(value) => push(&mut segs, &value, false, true),
+                    None => push(&mut segs, "$", false, false),
+                },
+                '`' => {
+                    let value = self.expand_backticks(chars, &mut i)?;
+                    push(&mut segs, &value, false, true);
+                }
+                c => {
+                    push(&mut segs, &c.to_string(), false, false);
+                    i += 1;
+                }
+            }
+        }
+        Ok(segs)
+    }
+
+    /// Expands the unquoted `~`, `~user`, `~+` or `~-` prefix that runs up to the
+    /// first `/` of a word, returning the directory and the index after the
+    /// prefix. Prefixes that cannot be resolved are left as written.
+    fn expand_tilde(&self, chars: &[char]) -> Option<(String, usize)> {
+        if chars.first() != Some(&'~') {
+            return None;
+        }
+        let end = chars.iter().position(|c| *c == '/').unwrap_or(chars.len());
+        let prefix: String = chars[1..end].iter().collect();
+        let dir = match prefix.as_str() {
+            "" => self
+                .get_var("HOME")
+                .or_else(|| home_dir(&self.get_var("USER")?))?,
+            "+" => self.get_var("PWD")?,
+            "-" => self.get_var("OLDPWD")?,
+            user if user
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')) =>
+            {
+                home_dir(user)?
+            }
+            _ => return None,
+        };
+        Some((dir, end))
+    }
+
+    /// Expands the inside of a double-quoted string starting at `i`, leaving `i`
+    /// after the closing quote.
+    fn expand_double_quoted(
+        &mut self,
+        chars: &[char],
+        i: &mut usize,
+        segs: &mut Vec<Segment>,
+    ) -> Result<(), ShellError> {
+        let start = segs.len();
+        // Whether the quotes held a `"$@"` that had no parameters to expand to.
+        let mut no_params = false;
+        while *i < chars.len() && chars[*i] != '"' {
+            match chars[*i] {
+                '$' if chars.get(*i + 1) == Some(&'@') || self.braced_params(chars, *i) => {
+                    // Every parameter becomes a field of its own.
+                    *i = match chars[*i + 1] {
+                        '{' => closing(chars, *i + 1).unwrap_or(chars.len()) + 1,
+                        _ => *i + 2,
+                    };
+                    let params = self.args.get(1..).unwrap_or_default().to_vec();
+                    no_params |= params.is_empty();
+                    for (n, param) in params.iter().enumerate() {
+                        if n > 0 {
+                            push_boundary(segs);
+                        }
+                        push(segs, param, true, false);
+                    }
+                }
+                '\\' if *i + 1 < chars.len() => {
+                    // Backslashes only escape the characters that are special here.
+                    match chars[*i + 1] {
+                        '\n' => {}
+                        c @ ('$' | '`' | '"' | '\\') => push(segs, &c.to_string(), true, false),
+                        c => push(segs, &format!("\\{}", c), true, false),
+                    }
+                    *i += 2;
+                }
+                '$' => {
+                    let value = self.expand_parameter(chars, i)?;
+                    push(segs, value.as_deref().unwrap_or("$"), true, false);
+                }
+                '`' => {
+                    let value = self.expand_backticks(chars, i)?;
+                    push(segs, &value, true, false);
+                }
+                c => {
+                    push(segs, &c.to_string(), true, false);
+                    *i += 1;
+                }
+            }
+        }
+        // An empty pair of quotes still produces a field, unless it only held a
+        // `"$@"` without parameters.
+        if segs.len() == start && !no_params {
+            push(segs, "", true, false);
+        }
+        *i += 1;
+        Ok(())
+    }
+
+    /// Whether the `$` at `i` starts a `${@}`, or a `${@-word}`, `${@=word}` or
+    /// `${@?word}` that expands to the parameters themselves rather than to
+    /// `word` or an error, each optionally written with a `:`.
+    fn braced_params(&self, chars: &[char], i: usize) -> bool {
+        if chars.get(i + 1) != Some(&'{') || chars.get(i + 2) != Some(&'@') {
+            return false;
+        }
+        let Some(end) = closing(chars, i + 1) else {
+            return false;
+        };
+        let value = self.get_var("@");
+        let (colon, op) = match &chars[i + 3..end] {
+            [] => return true,
+            [':', op, ..] => (true, *op),
+            [op, ..] => (false, *op),
+        };
+        let missing = value.is_none_or(|value| colon && value.is_empty());
+        matches!(op, '-' | '=' | '?') && !missing
+    }
+
+    /// Expands the parameter starting with the `$` at `i`, leaving `i` after it.
+    /// Returns `None` when the `$` does not start an expansion.
+    fn expand_parameter(
+        &mut self,
+        chars: &[char],
+        i: &mut usize,
+    ) -> Result<Option<String>, ShellError> {
+        let start = *i;
+        *i += 1;
+        match chars.get(*i) {
+            // `$((` is arithmetic when its two parentheses close together,
+            // otherwise it is a command substitution starting with a subshell.
+            Some('(')
+                if chars.get(*i + 1) == Some(&'(')
+                    && closing(chars, *i)
+                        .is_some_and(|end| closing(chars, *i + 1) == Some(end - 1)) =>
+            {
+                let end = closing(chars, *i).unwrap_or(chars.len());
+                *i = end + 1;
+                let expr: String = chars[start + 3..end - 1].iter().collect();
+                let expr = self.expand_string(&expr)?;
+                Ok(Some(self.arithmetic(&expr)?.to_string()))
+            }
+            Some('(') => match closing(chars, *i) {
+                Some(end) => {
+                    *i = end + 1;
+                    let source: String = chars[start + 2..end].iter().collect();
+                    Ok(Some(self.command_substitution(&source)?))
+                }
+                None => Err(ShellError::expansion(
+                    "missing \")\" in command substitution",
+                )),
+            },
+            Some('{') => match closing(chars, *i) {
+                Some(end) => {
+                    *i = end + 1;
+                    Ok(Some(self.expand_braced(&chars[start + 2..end])?))
+                }
+                None => Err(ShellError::expansion(
+                    "missing \"}\" in parameter expansion",
+                )),
+            },
+            Some(c) if is_special(*c) => {
+                *i += 1;
+                Ok(Some(self.get_var(&c.to_string()).unwrap_or_default()))
+            }
+            Some(c) if is_name_start(*c) => {
+                let end = chars[*i..]
+                    .iter()
+                    .position(|c| !is_name_char(*c))
+                    .map_or(chars.len(), |end| *i + end);
+                let name: String = chars[*i..end].iter().collect();
+                *i = end;
+                Ok(Some(self.get_var(&name).unwrap_or_default()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Expands the legacy `` `...` `` form of command substitution starting at
+    /// `i`, leaving `i` after the closing backtick.
+    fn expand_backticks(&mut self, chars: &[char], i: &mut usize) -> Result<String, ShellError> {
+        let mut source = String::new();
+        *i += 1;
+        while *i < chars.len() && chars[*i] != '`' {
+            // Inside backticks a backslash only escapes `$`, `` ` `` and itself.
+            match (chars[*i], chars.get(*i + 1)) {
+                ('\\', Some(c @ ('$' | '`' | '\\'))) => {
+                    source.push(*c);
+                    *i += 2;
+                }
+                (c, _) => {
+                    source.push(c);
+                    *i += 1;
+                }
+            }
+        }
+        *i += 1;
+        self.command_substitution(&source)
+    }
+
+    /// Runs `source` on a copy of the context and returns what it wrote to
+    /// stdout, without trailing newlines.
+    fn command_substitution(&mut self, source: &str) -> Result<String, ShellError> {
+        let mut lexer = Scanner::new(String::from(source));
+        lexer.scan_tokens()?;
+        let ast = parse_program(lexer)?;
+        let (mut reader, writer) =
+            pipe().map_err(|e| ShellError::io("Error when creating pipe", e))?;
+        let mut io = Io::default();
+        io.set(1, writer.into());
+        let mut ctx = self.clone();
+        // The program runs in its own thread while the output is read here, the
+        // pipe closes once it and every command it started are done with it.
+        let handle = thread::Builder::new()
+            .stack_size(STACK_SIZE)
+            .spawn(move || ctx.run_subshell(&ast, &io))
+            .map_err(|e| ShellError::io("Error when starting command substitution", e))?;
+        let mut out = Vec::new();
+        let read = reader.read_to_end(&mut out);
+        self.last_status = handle.join().unwrap_or(1);
+        self.subst_status = Some(self.last_status);
+        read.map_err(|e| ShellError::io("Error when reading command output", e))?;
+        let out = String::from_utf8_lossy(&out);
+        Ok(String::from(out.trim_end_matches('\n')))
+    }
+
+    /// Expands the inside of `${...}`: `NAME`, `#NAME` and the `-`, `=`, `+`
+    /// and `?` operators, each optionally prefixed with `:` to also apply when
+    /// the variable is set but empty.
+    fn expand_braced(&mut self, inner: &[char]) -> Result<String, ShellError> {
+        let text: String = inner.iter().collect();
+        if inner.len() > 1 && inner[0] == '#' {
+            let name: String = inner[1..].iter().collect();
+            let value = match name.strip_suffix(']').and_then(|name| name.split_once('[')) {
+                // `${#NAME[@]}` is the number of elements.
+                Some((name, "@" | "*")) => {
+                    return Ok(match self.arrays.get(name) {
+                        Some(values) => values.len(),
+                        None => self.get_var(name).iter().len(),
+                    }
+                    .to_string())
+                }
+                Some((name, subscript)) => self.element(name, subscript)?,
+                None => self.get_var(&name),
+            };
+            return Ok(value.unwrap_or_default().chars().count().to_string());
+        }
+        let name_len = match inner.first() {
+            Some(c) if c.is_ascii_digit() => inner
+                .iter()
+                .position(|c| !c.is_ascii_digit())
+                .unwrap_or(inner.len()),
+            Some(c) if is_special(*c) => 1,
+            Some(c) if is_name_start(*c) => inner
+                .iter()
+                .position(|c| !is_name_char(*c))
+                .unwrap_or(inner.len()),
+            _ => 0,
+        };
+        let bad_substitution = || ShellError::expansion(format!("${{{}}}: bad substitution", text));
+        if name_len == 0 {
+            return Err(bad_substitution());
+        }
+        let name: String = inner[..name_len].iter().collect();
+        let mut rest = &inner[name_len..];
+        let indexed = rest.first() == Some(&'[');
+        let value = match indexed {
+            true => {
+                let close = rest
+                    .iter()
+                    .position(|c| *c == ']')
+                    .ok_or_else(bad_substitution)?;
+                let subscript: String = rest[1..close].iter().collect();
+                rest = &rest[close + 1..];
+                self.element(&name, &subscript)?
+            }
+            false => self.get_var(&name),
+        };
+        if rest.is_empty() {
+            return Ok(value.unwrap_or_default());
+        }
+        let colon = rest[0] == ':';
+        if colon {
+            rest = &rest[1..];
+        }
+        let Some((&op, word)) = rest.split_first() else {
+            return Err(bad_substitution());
+        };
+        let missing = match &value {
+            Some(value) => colon && value.is_empty(),
+            None => true,
+        };
+        match op {
+            '-' if missing => Ok(joined(self.expand_segments(word)?)),
+            // Elements cannot be assigned to, there is no way to create arrays.
+            '=' if indexed => Err(bad_substitution()),
+            '=' if !is_name(&name) => Err(ShellError::expansion(format!(
+                "${}: cannot assign in this way",
+                name
+            ))),
+            '=' if missing => {
+                let word: String = joined(self.expand_segments(word)?);
+                self.set_var(&name, word.clone());
+                Ok(word)
+            }
+            '+' if missing => Ok(String::new()),
+            '+' => Ok(joined(self.expand_segments(word)?)),
+            '?' if missing => {
+                let word: String = joined(self.expand_segments(word)?);
+                Err(ShellError::expansion(match word.is_empty() {
+                    true => format!("{}: parameter null or not set", name),
+                    false => format!("{}: {}", name, word),
+                }))
+            }
+            '-' | '=' | '?' => Ok(value.unwrap_or_default()),
+            _ => Err(bad_substitution()),
+        }
+    }
+
+    /// Splits the results of unquoted expansions on the characters in `IFS`,
+    /// dropping fields that end up empty unless they were quoted.
+    fn split_fields(&self, segs: Vec<Segment>) -> Vec<Field> {
+        let ifs = self.get_var("IFS").unwrap_or_else(|| String::from(" \t\n"));
+        let mut fields = Vec::new();
+        let mut field = Field::default();
+        let mut started = false;
+        for seg in segs {
+            if seg.boundary {
+                fields.push(std::mem::take(&mut field));
+                started = false;
+                continue;
+            }
+            if !seg.split {
+                started |= seg.quoted || !seg.text.is_empty();
+                field.push(&seg.text, seg.quoted);
+                continue;
+            }
+            for c in seg.text.chars() {
+                if ifs.contains(c) {
+                    if started {
+                        fields.push(std::mem::take(&mut field));
+                        started = false;
+                    }
+                } else {
+                    field.push(&c.to_string(), false);
+                    started = true;
+                }
+            }
+        }
+        if started {
+            fields.push(field);
+        }
+        fields
+    }
+}
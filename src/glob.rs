@@ -0,0 +1,369 @@
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Characters with a special meaning in patterns, escaped with `\` to match them
+/// literally.
+pub const SPECIAL: [char; 5] = ['*', '?', '[', ']', '\\'];
+
+/// Whether `pattern` contains an unescaped wildcard.
+pub fn has_glob(pattern: &str) -> bool {
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '*' | '?' | '[' => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Removes the escaping backslashes from `pattern`.
+pub fn unescape(pattern: &str) -> String {
+    let mut text = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => text.extend(chars.next()),
+            c => text.push(c),
+        }
+    }
+    text
+}
+
+/// Matches `c` against the POSIX character class `name`, as in `[[:alpha:]]`.
+fn class_matches(name: &str, c: char) -> bool {
+    match name {
+        "alnum" => c.is_alphanumeric(),
+        "alpha" => c.is_alphabetic(),
+        "blank" => c == ' ' || c == '\t',
+        "cntrl" => c.is_control(),
+        "digit" => c.is_ascii_digit(),
+        "graph" => c.is_ascii_graphic(),
+        "lower" => c.is_lowercase(),
+        "print" => c.is_ascii_graphic() || c == ' ',
+        "punct" => c.is_ascii_punctuation(),
+        "space" => c.is_whitespace(),
+        "upper" => c.is_uppercase(),
+        "xdigit" => c.is_ascii_hexdigit(),
+        _ => false,
+    }
+}
+
+/// Matches `c` against the bracket expression starting at `p[start]`, returning
+/// whether it matched and the index after the closing `]`. Returns `None` when
+/// the bracket is never closed, in which case `[` is an ordinary character.
+fn bracket(p: &[char], start: usize, c: char) -> Option<(bool, usize)> {
+    let mut i = start + 1;
+    let negate = matches!(p.get(i), Some('!' | '^'));
+    if negate {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let mut lo = *p.get(i)?;
+        if lo == ']' && !first {
+            return Some((matched != negate, i + 1));
+        }
+        first = false;
+        if lo == '[' && p.get(i + 1) == Some(&':') {
+            let rest: String = p[i + 2..].iter().collect();
+            if let Some(end) = rest.find(":]") {
+                matched |= class_matches(&rest[..end], c);
+                i += 2 + rest[..end].chars().count() + 2;
+                continue;
+            }
+        }
+        if lo == '\\' {
+            i += 1;
+            lo = *p.get(i)?;
+        }
+        i += 1;
+        let mut hi = lo;
+        if p.get(i) == Some(&'-') && p.get(i + 1).is_some_and(|c| *c != ']') {
+            hi = p[i + 1];
+            i += 2;
+            if hi == '\\' {
+                hi = *p.get(i)?;
+                i += 1;
+            }
+        }
+        matched |= lo <= c && c <= hi;
+    }
+}
+
+/// Matches `text` against the shell pattern `pattern` with `*`, `?` and
+/// `[...]` wildcards, a `\` makes the next character match literally.
+pub fn matches(pattern: &str, text: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let t: Vec<char> = text.chars().collect();
+    let (mut pi, mut ti) = (0, 0);
+    // Where to resume after the last `*` when the rest fails to match.
+    let mut star: Option<(usize, usize)> = None;
+    while ti < t.len() {
+        let step = match p.get(pi) {
+            Some('*') => {
+                star = Some((pi + 1, ti));
+                pi += 1;
+                continue;
+            }
+            Some('?') => Some(pi + 1),
+            Some('[') => match bracket(&p, pi, t[ti]) {
+                Some((true, next)) => Some(next),
+                Some((false, _)) => None,
+                None => (t[ti] == '[').then_some(pi + 1),
+            },
+            Some('\\') if pi + 1 < p.len() => (p[pi + 1] == t[ti]).then_some(pi + 2),
+            Some(c) => (*c == t[ti]).then_some(pi + 1),
+            None => None,
+        };
+        match (step, star) {
+            (Some(next), _) => {
+                pi = next;
+                ti += 1;
+            }
+            (None, Some((star_pi, star_ti))) => {
+                pi = star_pi;
+                ti = star_ti + 1;
+                star = Some((star_pi, star_ti + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+/// Lists the entries of `dir` whose names match `pattern`. Names starting with
+/// `.` only match when the pattern does too, unless `dotglob` is set, and `.`
+/// and `..` never match. Names that are not valid UTF-8 are matched lossily but
+/// returned as they are.
+fn read_matching(dir: &Path, pattern: &str, dotglob: bool) -> Vec<OsString> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let explicit_dot = pattern.starts_with('.') || pattern.starts_with("\\.");
+    let mut names: Vec<OsString> = entries
+        .filter_map(|entry| Some(entry.ok()?.file_name()))
+        .filter(|name| {
+            let name = name.to_string_lossy();
+            (!name.starts_with('.') || explicit_dot || dotglob) && matches(pattern, &name)
+        })
+        .collect();
+    names.sort();
+    names
+}
+
+/// Joins the pieces of a path.
+fn concat(parts: &[&OsStr]) -> OsString {
+    parts.iter().copied().collect()
+}
+
+/// Collects `prefix` and every directory below it for a `**` component.
+fn descend(cwd: &Path, prefix: &OsStr, dotglob: bool, out: &mut Vec<OsString>) {
+    out.push(prefix.to_owned());
+    for name in read_matching(&cwd.join(prefix), "*", dotglob) {
+        let path = concat(&[prefix, &name, OsStr::new("/")]);
+        let is_dir = fs::symlink_metadata(cwd.join(&path)).is_ok_and(|meta| meta.is_dir());
+        if is_dir {
+            descend(cwd, &path, dotglob, out);
+        }
+    }
+}
+
+/// Expands `pattern` into the sorted paths it matches relative to `cwd`, a `**`
+/// component matches any number of directories.
+pub fn expand(pattern: &str, cwd: &Path, dotglob: bool) -> Vec<OsString> {
+    let absolute = pattern.starts_with('/');
+    let mut prefixes = vec![OsString::from(if absolute { "/" } else { "" })];
+    let components: Vec<&str> = pattern.split('/').filter(|c| !c.is_empty()).collect();
+    for (i, component) in components.iter().enumerate() {
+        let last = i + 1 == components.len();
+        let mut next = Vec::new();
+        for prefix in prefixes {
+            if *component == "**" {
+                descend(cwd, &prefix, dotglob, &mut next);
+                if last {
+                    // A trailing `**` also matches the files in every directory.
+                    let dirs = std::mem::take(&mut next);
+                    for dir in dirs {
+                        next.extend(
+                            read_matching(&cwd.join(&dir), "*", dotglob)
+                                .into_iter()
+                                .map(|name| concat(&[&dir, &name])),
+                        );
+                    }
+                }
+                continue;
+            }
+            let sep = OsStr::new(if last { "" } else { "/" });
+            if !has_glob(component) {
+                let literal = unescape(component);
+                next.push(concat(&[&prefix, OsStr::new(&literal), sep]));
+                continue;
+            }
+            for name in read_matching(&cwd.join(&prefix), component, dotglob) {
+                next.push(concat(&[&prefix, &name, sep]));
+            }
+        }
+        prefixes = next;
+    }
+    let mut paths: Vec<OsString> = prefixes
+        .into_iter()
+        .filter(|path| !path.is_empty() && fs::symlink_metadata(cwd.join(path)).is_ok())
+        .filter(|path| !pattern.ends_with('/') || cwd.join(path).is_dir())
+        .map(|path| {
+            let bytes = path.as_bytes();
+            let end = bytes.len() - bytes.iter().rev().take_while(|b| **b == b'/').count();
+            match (end, pattern.ends_with('/')) {
+                // The root stays `/`.
+                (0, _) => path,
+                (end, true) => concat(&[OsStr::from_bytes(&bytes[..end]), OsStr::new("/")]),
+                (end, false) => OsStr::from_bytes(&bytes[..end]).to_owned(),
+            }
+        })
+        .collect();
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::path::PathBuf;
+
+    #[test]
+    fn wildcards() {
+        assert!(matches("*.rs", "main.rs"));
+        assert!(!matches("*.rs", "main.rsx"));
+        assert!(matches("a*b*c", "abxbc"));
+        assert!(matches("?", "é"));
+        assert!(!matches("?", ""));
+        assert!(matches("*", ""));
+    }
+
+    #[test]
+    fn brackets() {
+        assert!(matches("[abc]", "b"));
+        assert!(matches("[a-c]x", "cx"));
+        assert!(!matches("[a-c]", "d"));
+        assert!(matches("[!a]*", "bar"));
+        assert!(!matches("[!a]*", "abc"));
+        assert!(matches("[^a]", "b"));
+        // A `]` right after the opening bracket is part of the set.
+        assert!(matches("[]]", "]"));
+        assert!(matches("[!]]", "a"));
+        assert!(matches("[\\]]", "]"));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(matches("[[:digit:]]x", "7x"));
+        assert!(!matches("[[:digit:]]x", "ax"));
+        assert!(matches("[[:alpha:][:digit:]]", "q"));
+        assert!(matches("[![:space:]]", "a"));
+        assert!(!matches("[![:space:]]", " "));
+        assert!(matches("[[:upper:]]*", "Makefile"));
+        assert!(!matches("[[:bogus:]]", "a"));
+    }
+
+    #[test]
+    fn escapes_and_unclosed_brackets() {
+        assert!(matches("\\*", "*"));
+        assert!(!matches("\\*", "x"));
+        assert!(matches("a\\?", "a?"));
+        // An unclosed `[` is an ordinary character.
+        assert!(matches("a[b", "a[b"));
+        assert!(!matches("a[b", "ab"));
+        assert!(has_glob("a*"));
+        assert!(!has_glob("a\\*"));
+        assert_eq!(unescape("a\\*b\\\\"), "a*b\\");
+    }
+
+    /// A scratch directory holding `files`, removed when dropped.
+    struct Tree(PathBuf);
+
+    impl Tree {
+        fn new(name: &str, files: &[&str]) -> Self {
+            let dir = env::temp_dir().join(format!("wts-glob-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            for file in files {
+                let path = dir.join(file);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, "").unwrap();
+            }
+            Self(dir)
+        }
+
+        fn expand(&self, pattern: &str, dotglob: bool) -> Vec<String> {
+            expand(pattern, &self.0, dotglob)
+                .into_iter()
+                .map(|path| path.into_string().unwrap())
+                .collect()
+        }
+    }
+
+    impl Drop for Tree {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn expand_paths() {
+        let tree = Tree::new(
+            "expand",
+            &[
+                "a.rs",
+                "b.rs",
+                "c.txt",
+                ".hidden.rs",
+                "src/x.rs",
+                "src/deep/y.rs",
+            ],
+        );
+        assert_eq!(tree.expand("*.rs", false), ["a.rs", "b.rs"]);
+        // Dotfiles only match a leading `.` in the pattern, or with `dotglob`.
+        assert_eq!(tree.expand(".*.rs", false), [".hidden.rs"]);
+        assert_eq!(tree.expand("*.rs", true), [".hidden.rs", "a.rs", "b.rs"]);
+        assert_eq!(tree.expand("*/", false), ["src/"]);
+        assert_eq!(tree.expand("src/*/*.rs", false), ["src/deep/y.rs"]);
+        assert_eq!(tree.expand("nothing*", false), Vec::<String>::new());
+        assert_eq!(tree.expand("\\*.rs", false), Vec::<String>::new());
+    }
+
+    #[test]
+    fn expand_recursive() {
+        let tree = Tree::new(
+            "recursive",
+            &["top.rs", "src/x.rs", "src/deep/y.rs", ".git/z.rs"],
+        );
+        assert_eq!(
+            tree.expand("**/*.rs", false),
+            ["src/deep/y.rs", "src/x.rs", "top.rs"]
+        );
+        assert_eq!(
+            tree.expand("src/**", false),
+            ["src/deep", "src/deep/y.rs", "src/x.rs"]
+        );
+        assert!(tree
+            .expand("**/*.rs", true)
+            .contains(&String::from(".git/z.rs")));
+    }
+
+    #[test]
+    fn expand_keeps_names_that_are_not_utf8() {
+        use std::os::unix::ffi::OsStrExt;
+        let tree = Tree::new("bytes", &[]);
+        fs::create_dir_all(&tree.0).unwrap();
+        let name = OsStr::from_bytes(b"caf\xe9.txt");
+        fs::write(tree.0.join(name), "").unwrap();
+        assert_eq!(expand("*.txt", &tree.0, false), [name]);
+    }
+}
@@ -1,358 +1,1008 @@
-use crate::ast::{Expr, Kind, Symbol, AST};
-use std::fs::{File, OpenOptions};
+use crate::ast::{CaseEnd, Command, CommandKind, Expr, Redirect, Symbol, AST};
+use crate::error::ShellError;
+use crate::glob;
+use crate::jobs::{self, Job, Tracker, Waited};
+use crate::signals::Condition;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::os::fd::{AsFd, AsRawFd, OwnedFd};
+use std::os::unix::process::CommandExt;
 use std::path::Path;
+use std::process::{self, Child, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
 use std::{
-    fs,
-    io::Write,
-    io::{stdin, stdout, Error},
-    process::{Child, Command, Stdio},
+    io,
+    io::{pipe, Write},
 };
 
-#[derive(Debug, Clone)]
-pub struct Value {
-    sym: Symbol,
-    val: String,
-    flags: Vec<String>,
-    args: Vec<String>,
+/// The descriptors handed to a command that differ from the shell's own, `None`
+/// marking one closed with `N>&-`.
+#[derive(Debug, Default)]
+pub struct Io {
+    fds: BTreeMap<i32, Option<OwnedFd>>,
 }
 
-#[derive(PartialEq, Eq, Clone, Copy, Debug)]
-pub enum ExecType {
-    Normal,
-    DelayExec,
-    Quiet,
+impl Io {
+    pub fn try_clone(&self) -> io::Result<Self> {
+        let mut fds = BTreeMap::new();
+        for (&fd, handle) in &self.fds {
+            fds.insert(fd, handle.as_ref().map(OwnedFd::try_clone).transpose()?);
+        }
+        Ok(Self { fds })
+    }
+    /// Returns a handle to descriptor `fd`, duplicating the shell's own stream if
+    /// it has not been redirected. Descriptors above 2 have to be opened first.
+    pub fn get(&self, fd: i32) -> io::Result<OwnedFd> {
+        let closed = || io::Error::from_raw_os_error(libc::EBADF);
+        match self.fds.get(&fd) {
+            Some(Some(handle)) => handle.try_clone(),
+            Some(None) => Err(closed()),
+            None => match fd {
+                0 => io::stdin().as_fd().try_clone_to_owned(),
+                1 => io::stdout().as_fd().try_clone_to_owned(),
+                2 => io::stderr().as_fd().try_clone_to_owned(),
+                _ => Err(closed()),
+            },
+        }
+    }
+    /// Opens descriptor `fd` as a file that the shell itself can write to.
+    pub fn file(&self, fd: i32) -> io::Result<File> {
+        Ok(File::from(self.get(fd)?))
+    }
+    /// Reports `err` on this stderr, falling back to the shell's own.
+    pub fn report(&self, err: &ShellError) {
+        match self.file(2) {
+            Ok(mut stderr) => {
+                let _ = writeln!(stderr, "{}", err);
+            }
+            Err(_) => eprintln!("{}", err),
+        }
+    }
+    /// Points descriptor `fd` at `handle`.
+    pub fn set(&mut self, fd: i32, handle: OwnedFd) {
+        self.fds.insert(fd, Some(handle));
+    }
+    /// Closes descriptor `fd`, as `N>&-` does.
+    fn close(&mut self, fd: i32) {
+        self.fds.insert(fd, None);
+    }
+    /// Hands the descriptors over to `proc`. The standard streams go through
+    /// `Stdio`, the others are installed in the child between fork and exec.
+    fn install(self, proc: &mut process::Command) {
+        let mut extra = Vec::new();
+        let mut closed = Vec::new();
+        for (fd, handle) in self.fds {
+            match (fd, handle) {
+                (0, Some(handle)) => {
+                    proc.stdin(Stdio::from(handle));
+                }
+                (1, Some(handle)) => {
+                    proc.stdout(Stdio::from(handle));
+                }
+                (2, Some(handle)) => {
+                    proc.stderr(Stdio::from(handle));
+                }
+                (fd, Some(handle)) => extra.push((fd, handle)),
+                (fd, None) => closed.push(fd),
+            }
+        }
+        if extra.is_empty() && closed.is_empty() {
+            return;
+        }
+        // Every handle is first copied above all targets so that installing one
+        // descriptor cannot overwrite the source of another. `F_DUPFD` picks the
+        // lowest free slot, so a copy never lands on a source not yet copied.
+        let above = extra.iter().map(|(fd, _)| *fd).max().unwrap_or(0).max(2) + 1;
+        let mut copies = vec![-1; extra.len()];
+        let hook = move || {
+            for ((_, handle), copy) in extra.iter().zip(copies.iter_mut()) {
+                // SAFETY: only async-signal-safe calls on descriptors we own.
+                *copy = unsafe { libc::fcntl(handle.as_raw_fd(), libc::F_DUPFD, above) };
+                if *copy < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            for ((fd, _), copy) in extra.iter().zip(&copies) {
+                // SAFETY: as above, `dup2` leaves close-on-exec cleared on `fd`.
+                unsafe {
+                    if libc::dup2(*copy, *fd) < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    libc::close(*copy);
+                }
+            }
+            for fd in &closed {
+                // SAFETY: closing a descriptor that may not be open is harmless.
+                unsafe { libc::close(*fd) };
+            }
+            Ok(())
+        };
+        // SAFETY: the hook neither allocates nor takes locks.
+        unsafe { proc.pre_exec(hook) };
+    }
+}
+
+/// Something started by a pipeline stage that has to be waited on.
+enum Running<'scope> {
+    Child(Child),
+    Thread(thread::ScopedJoinHandle<'scope, i32>),
+    /// A stage that finished without starting anything, holding its status.
+    Done(i32),
+}
+
+/// A jump out of the normal order of the program, set by a builtin and
+/// handled by whatever it jumps out of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    /// `break N`, leaving the N innermost loops.
+    Break(usize),
+    /// `continue N`, going on with the next iteration of the Nth loop.
+    Continue(usize),
+    /// `return N`, leaving the function being run.
+    Return(i32),
+    /// `exit N`, stopping the program.
+    Exit(i32),
+}
+
+/// How deep functions can call each other when `FUNCNEST` is not set.
+const MAX_CALL_DEPTH: usize = 1000;
+
+/// Stack size of the threads running the shell's own code, with room for
+/// `MAX_CALL_DEPTH` nested calls. Only the part in use takes up memory.
+pub const STACK_SIZE: usize = 64 << 20;
+
+/// Spawns a scoped thread running the shell's own code, such as a builtin in a
+/// pipeline.
+fn spawn_scoped<'scope, T: Send + 'scope>(
+    scope: &'scope thread::Scope<'scope, '_>,
+    f: impl FnOnce() -> T + Send + 'scope,
+) -> Result<thread::ScopedJoinHandle<'scope, T>, ShellError> {
+    thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn_scoped(scope, f)
+        .map_err(|e| ShellError::io("Error when starting thread", e))
+}
+
+/// Decodes expanded words for the shell's own use.
+fn lossy(words: &[OsString]) -> Vec<String> {
+    words
+        .iter()
+        .map(|word| word.to_string_lossy().into_owned())
+        .collect()
+}
+
+/// A pipeline stage whose words have been expanded and whose redirections have
+/// been applied, ready to be started.
+enum Stage<'a> {
+    Simple {
+        assigns: Vec<(String, String)>,
+        /// The words as handed to a program, which functions and builtins see
+        /// decoded lossily.
+        words: Vec<OsString>,
+        io: Io,
+    },
+    Subshell {
+        ast: &'a AST,
+        io: Io,
+    },
+    /// A brace group, a `[[ ]]`, `(( ))`, `if`, `case`, `while`, `until` or `for`
+    /// command, or a function definition.
+    Compound {
+        kind: &'a CommandKind,
+        io: Io,
+    },
+    /// A stage that has nothing left to run, holding its exit status.
+    Done(i32),
 }
 
 #[derive(Debug, Clone)]
 pub struct ExecContext {
     pub cur_dir: Box<Path>,
+    /// Exit status of the last command that was run, exposed as `$?`.
+    pub last_status: i32,
+    /// Shell variables, seeded from the environment the shell was started with.
+    pub vars: HashMap<String, String>,
+    /// Names of the variables that are passed on to the environment of commands.
+    pub exported: HashSet<String>,
+    /// The original name and value of inherited variables that are not valid
+    /// UTF-8, by their decoded name.
+    pub inherited: HashMap<String, (OsString, OsString)>,
+    /// Status of the last command substitution while expanding a command, which
+    /// becomes the status of a command made only of assignments.
+    pub subst_status: Option<i32>,
+    /// Names of the options turned on with `set -o`.
+    pub options: HashSet<String>,
+    /// The name of the shell or script as `$0`, followed by the positional
+    /// parameters `$1`, `$2`, ...
+    pub args: Vec<String>,
+    /// Set by `break`, `continue` and `exit` to stop running commands until
+    /// whatever they jump out of is reached.
+    pub flow: Option<Flow>,
+    /// Number of loops the command being run is nested in, within the function
+    /// being run.
+    pub loop_depth: usize,
+    /// Indexed arrays, which are only set by the shell itself, as with the
+    /// `BASH_REMATCH` array of `[[ ]]`.
+    pub arrays: HashMap<String, Vec<String>>,
+    /// Functions by name, along with their bodies.
+    pub functions: HashMap<String, Arc<AST>>,
+    /// The values variables had before they were made local with `local`, one
+    /// map per function call being run, innermost last. `None` for variables
+    /// that were unset.
+    pub locals: Vec<HashMap<String, Option<String>>>,
+    /// Source of the program being interpreted, for pointing errors at it.
+    pub source: Arc<str>,
+    /// Background jobs started with `&`, shared by the copies of the context
+    /// that subshells and builtins in a pipeline run on. Jobs start their own.
+    pub jobs: Arc<Mutex<Vec<Job>>>,
+    /// The latest background job, whose pid is `$!`.
+    pub last_job: Option<Arc<Tracker>>,
+    /// The job or foreground pipeline being run, whose process group the
+    /// commands started are put in. Without job control only jobs have one.
+    pub group: Option<Arc<Tracker>>,
+    /// The commands set with `trap`, by when they run. Signals that are ignored
+    /// have an empty program.
+    pub traps: BTreeMap<Condition, Arc<AST>>,
+    /// Whether the command being run is tested by `if`, `while`, `until`, `&&`
+    /// or `||`, where failing does not set off the `ERR` trap.
+    pub testing: bool,
+    /// Whether the commands of a trap are being run.
+    pub in_trap: bool,
 }
 impl ExecContext {
-    pub fn new(path: String) -> Self {
+    pub fn new(path: &Path, args: Vec<String>) -> Self {
+        // Names and values that are not valid UTF-8 are decoded lossily, as
+        // scripts are, and their original bytes are kept for commands.
+        let mut vars = HashMap::new();
+        let mut inherited = HashMap::new();
+        for (raw_name, raw_value) in env::vars_os() {
+            let name = raw_name.to_string_lossy().into_owned();
+            let value = raw_value.to_string_lossy().into_owned();
+            if raw_name.to_str().is_none() || raw_value.to_str().is_none() {
+                inherited.insert(name.clone(), (raw_name, raw_value));
+            }
+            vars.insert(name, value);
+        }
+        vars.insert(String::from("PWD"), path.to_string_lossy().into_owned());
         Self {
-            cur_dir: Path::new(&path).into(),
+            cur_dir: path.into(),
+            last_status: 0,
+            exported: vars.keys().cloned().collect(),
+            vars,
+            inherited,
+            subst_status: None,
+            options: HashSet::new(),
+            args,
+            flow: None,
+            loop_depth: 0,
+            arrays: HashMap::new(),
+            functions: HashMap::new(),
+            locals: Vec::new(),
+            source: Arc::from(""),
+            jobs: Arc::default(),
+            last_job: None,
+            group: None,
+            traps: BTreeMap::new(),
+            testing: false,
+            in_trap: false,
         }
     }
-    fn exec_cmds(&self, cmds: Vec<Value>) -> Vec<u8> {
-        let mut out = Vec::new();
-        for cmd in cmds {
-            if cmd.sym == Symbol::Cmd {
-                let proc = Command::new(&cmd.val)
-                    .args(cmd.flags)
-                    .args(cmd.args)
-                    .current_dir(self.cur_dir.clone())
-                    .spawn();
-                if proc.is_ok() {
-                    let child = proc.unwrap();
-                    let mut output = child
-                        .wait_with_output()
-                        .expect("Error occured when parsing output")
-                        .stdout;
-                    out.append(&mut output);
-                } else {
-                    println!(
-                        "Error when executing command \"{}\"\n {:#?}",
-                        cmd.val.clone().to_string(),
-                        proc
-                    );
-                }
-            } else {
-                out.append(&mut cmd.val.as_bytes().to_vec());
+
+    /// Looks up a variable or one of the special parameters.
+    pub fn get_var(&self, name: &str) -> Option<String> {
+        match name {
+            "?" => Some(self.last_status.to_string()),
+            "$" => Some(process::id().to_string()),
+            "!" => self.last_job_pid().map(|pid| pid.to_string()),
+            "#" => Some(self.args.len().saturating_sub(1).to_string()),
+            // Without positional parameters `$@` and `$*` are unset.
+            "@" | "*" if self.args.len() < 2 => None,
+            "@" => Some(self.args.get(1..).unwrap_or_default().join(" ")),
+            "*" => {
+                // `$*` joins the parameters with the first character of `IFS`.
+                let ifs = self.get_var("IFS").unwrap_or_else(|| String::from(" "));
+                let sep = ifs.chars().next().map(String::from).unwrap_or_default();
+                Some(self.args.get(1..).unwrap_or_default().join(&sep))
+            }
+            _ if name.chars().all(|c| c.is_ascii_digit()) => {
+                self.args.get(name.parse::<usize>().ok()?).cloned()
             }
+            // An array on its own stands for its first element.
+            _ => self
+                .vars
+                .get(name)
+                .or_else(|| self.arrays.get(name)?.first())
+                .cloned(),
         }
-        return out;
-    }
-    fn pipe_cmds(&self, cmds: Vec<Value>, input: Vec<u8>) -> Vec<u8> {
-        let mut out = Vec::new();
-        for cmd in cmds {
-            if cmd.sym == Symbol::Cmd {
-                let proc = Command::new(&cmd.val)
-                    .args(cmd.flags)
-                    .args(cmd.args)
-                    .stdin(Stdio::piped())
-                    .stdout(Stdio::piped())
-                    .current_dir(self.cur_dir.clone())
-                    .spawn();
-                if proc.is_ok() {
-                    let mut child = proc.unwrap();
-                    child
-                        .stdin
-                        .as_mut()
-                        .unwrap()
-                        .write_all(&input)
-                        .expect("Error when writing to file.");
-                    out.append(
-                        &mut child
-                            .wait_with_output()
-                            .expect("Failed to read stdout.")
-                            .stdout,
-                    );
-                } else {
-                    println!(
-                        "Error when executing command \"{}\"\n {:#?}",
-                        cmd.val.clone().to_string(),
-                        proc
-                    );
+    }
+    pub fn set_var(&mut self, name: &str, value: String) {
+        self.vars.insert(String::from(name), value);
+    }
+    /// Whether the option `name` has been turned on with `set -o`.
+    pub fn option(&self, name: &str) -> bool {
+        self.options.contains(name)
+    }
+    /// The exported variables, which make up the environment of every command.
+    pub fn environment(&self) -> Vec<(&String, &String)> {
+        let mut env: Vec<_> = self
+            .vars
+            .iter()
+            .filter(|(name, _)| self.exported.contains(*name))
+            .collect();
+        env.sort();
+        env
+    }
+    /// The environment handed to commands, where inherited variables that are
+    /// not valid UTF-8 keep their original bytes until the shell changes them.
+    fn command_environment(&self) -> Vec<(OsString, OsString)> {
+        self.environment()
+            .into_iter()
+            .map(|(name, value)| match self.inherited.get(name) {
+                Some((raw_name, raw_value)) if raw_value.to_string_lossy() == **value => {
+                    (raw_name.clone(), raw_value.clone())
                 }
+                _ => (name.into(), value.into()),
+            })
+            .collect()
+    }
+
+    /// Applies `redirects` from left to right on top of `io`.
+    fn redirect(&mut self, io: &mut Io, redirects: &[Redirect]) -> Result<(), ShellError> {
+        for redirect in redirects {
+            let target = match redirect.symbol {
+                Symbol::DoubleRedirLeft => self
+                    .expand_heredoc(&redirect.target)
+                    .map_err(|e| e.located(&self.source, redirect.span))?,
+                Symbol::DoubleRedirLeftQuoted => redirect.target.clone(),
+                Symbol::HereString => self
+                    .expand_string(&redirect.target)
+                    .map(|text| text + "\n")
+                    .map_err(|e| e.located(&self.source, redirect.span))?,
+                _ => self
+                    .expand_string(&redirect.target)
+                    .map_err(|e| e.located(&self.source, redirect.span))?,
+            };
+            self.open_redirect(io, redirect, &target)
+                .map_err(|e| ShellError::io(&target, e))?;
+        }
+        Ok(())
+    }
+
+    /// Creates or truncates `path` for `>`. With `noclobber` set, existing
+    /// regular files are left alone and only `>|` can overwrite them.
+    fn create(&self, path: &Path) -> io::Result<File> {
+        if !self.option("noclobber") {
+            return File::create(path);
+        }
+        match OpenOptions::new().write(true).create_new(true).open(path) {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                if fs::metadata(path)?.is_file() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        "cannot overwrite existing file",
+                    ));
+                }
+                OpenOptions::new().write(true).open(path)
             }
+            result => result,
         }
-        return out;
     }
 
-    fn redir_right(&self, expr: &Expr) -> Option<Value> {
-        let left = self
-            .interpret_program(expr.left.as_ref().unwrap(), ExecType::Quiet)
-            .unwrap();
-        let right = self
-            .interpret_program(expr.right.as_ref().unwrap(), ExecType::DelayExec)
-            .unwrap();
-        if right[0].sym != Symbol::File {
-            println!("{:#?}", right);
-            println!("Expected some type of file.");
-            return None;
+    /// Opens the expanded `target` of a single redirection and installs it in
+    /// `io`.
+    fn open_redirect(&self, io: &mut Io, redirect: &Redirect, target: &str) -> io::Result<()> {
+        let path = self.cur_dir.join(target);
+        let handle: OwnedFd = match redirect.symbol {
+            Symbol::RedirLeft => File::open(path)?.into(),
+            Symbol::RedirRight => self.create(&path)?.into(),
+            Symbol::ClobberRedirRight => File::create(path)?.into(),
+            Symbol::ReadWriteRedir => OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?
+                .into(),
+            Symbol::DoubleRedirRight => OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(path)?
+                .into(),
+            // The text is fed through a pipe by a thread of its own, so that it
+            // can be larger than the pipe's buffer.
+            Symbol::DoubleRedirLeft | Symbol::DoubleRedirLeftQuoted | Symbol::HereString => {
+                let body = String::from(target);
+                let (reader, mut writer) = pipe()?;
+                thread::spawn(move || {
+                    let _ = writer.write_all(body.as_bytes());
+                });
+                reader.into()
+            }
+            Symbol::DupRedirLeft | Symbol::DupRedirRight if target == "-" => {
+                io.close(redirect.fd);
+                return Ok(());
+            }
+            Symbol::DupRedirLeft | Symbol::DupRedirRight => match target.parse::<i32>() {
+                Ok(fd) => io.get(fd)?,
+                // `>&file` is an older spelling of `&>file`.
+                Err(_) if redirect.symbol == Symbol::DupRedirRight && redirect.fd == 1 => {
+                    let handle: OwnedFd = self.create(&path)?.into();
+                    io.set(2, handle.try_clone()?);
+                    handle
+                }
+                Err(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "ambiguous redirect",
+                    ))
+                }
+            },
+            // Both stdout and stderr go to the file.
+            Symbol::AndRedirRight | Symbol::AndDoubleRedirRight => {
+                let handle: OwnedFd = match redirect.symbol {
+                    Symbol::AndRedirRight => self.create(&path)?.into(),
+                    _ => OpenOptions::new()
+                        .append(true)
+                        .create(true)
+                        .open(path)?
+                        .into(),
+                };
+                io.set(2, handle.try_clone()?);
+                handle
+            }
+            Symbol::Pipe | Symbol::AndIf | Symbol::OrIf => return Ok(()),
+        };
+        io.set(redirect.fd, handle);
+        Ok(())
+    }
+
+    /// Expands the words of `cmd` and applies its redirections on top of `io`.
+    /// A command made only of assignments sets those variables in the shell.
+    fn prepare_command<'a>(
+        &mut self,
+        cmd: &'a Command,
+        mut io: Io,
+    ) -> Result<Stage<'a>, ShellError> {
+        let mut words = Vec::new();
+        let mut assigns = Vec::new();
+        self.subst_status = None;
+        if let CommandKind::Simple {
+            assigns: raw_assigns,
+            words: raw_words,
+        } = &cmd.kind
+        {
+            let source = self.source.clone();
+            let located = |e: ShellError| e.located(&source, cmd.span);
+            for word in raw_words {
+                words.extend(self.expand_word(word).map_err(located)?);
+            }
+            for (name, value) in raw_assigns {
+                assigns.push((name.clone(), self.expand_string(value).map_err(located)?));
+            }
         }
-        let path = fs::metadata(&right[0].val);
-        let buf = left
-            .iter()
-            .fold(String::from(""), |cur, next| cur + &next.val);
-        if path.is_ok() {
-            let meta = path.unwrap();
-            if meta.is_file() {
-                if expr.symbol == Symbol::DoubleRedirRight {
-                    let mut file: File =
-                        OpenOptions::new().append(true).open(&right[0].val).unwrap();
-                    file.write_all(buf.as_bytes())
-                        .expect("Error when writing to file.");
-                } else {
-                    fs::write(&right[0].val, buf).expect("Error when writing to file");
+        self.redirect(&mut io, &cmd.redirects)?;
+        Ok(match &cmd.kind {
+            CommandKind::Simple { .. } if words.is_empty() => {
+                for (name, value) in assigns {
+                    self.set_var(&name, value);
                 }
-            } else if meta.is_dir() {
-                println!("Expected some type of file, got directory.");
-                return None;
-            }
-        } else {
-            let file = File::create(&right[0].val);
-            if file.is_ok() {
-                file.unwrap()
-                    .write_all(buf.as_bytes())
-                    .expect("Error when writing to file");
-            } else {
-                println!("Error when opening file: {:#?}", file);
+                Stage::Done(self.subst_status.unwrap_or(0))
             }
+            CommandKind::Simple { .. } => Stage::Simple { assigns, words, io },
+            CommandKind::Subshell(ast) => Stage::Subshell { ast, io },
+            kind => Stage::Compound { kind, io },
+        })
+    }
+
+    /// A copy of the shell for a pipeline stage run on a thread of its own,
+    /// which like a subshell leaves the traps to the shell.
+    fn fork(&self) -> Self {
+        let mut ctx = self.clone();
+        ctx.reset_traps();
+        ctx
+    }
+
+    /// Whether `words` calls a function.
+    fn is_function(&self, words: &[OsString]) -> bool {
+        self.functions.contains_key(&*words[0].to_string_lossy())
+    }
+
+    /// The source text of the pipeline `cmds`.
+    fn source_of(&self, cmds: &[Command]) -> &str {
+        let span = cmds[0].span.to(cmds[cmds.len() - 1].span);
+        &self.source[span.start..span.end]
+    }
+
+    /// Starts a single pipeline stage without waiting on it.
+    fn spawn_command<'scope>(
+        &'scope self,
+        scope: &'scope thread::Scope<'scope, '_>,
+        stage: Stage<'scope>,
+    ) -> Result<Running<'scope>, ShellError> {
+        match stage {
+            Stage::Simple { assigns, words, io } if self.is_function(&words) => {
+                let words = lossy(&words);
+                let mut ctx = self.fork();
+                Ok(Running::Thread(spawn_scoped(scope, move || {
+                    ctx.call_function(assigns, &words, &io).unwrap_or_else(|e| {
+                        io.report(&e);
+                        e.status()
+                    })
+                })?))
+            }
+            Stage::Simple { assigns, words, io } if self.is_builtin(&lossy(&words)) => {
+                let words = lossy(&words);
+                let mut ctx = self.fork();
+                Ok(Running::Thread(spawn_scoped(scope, move || {
+                    ctx.run_builtin(assigns, &words, &io)
+                })?))
+            }
+            Stage::Simple { assigns, words, io } => {
+                let mut proc = process::Command::new(&words[0]);
+                proc.args(&words[1..])
+                    .env_clear()
+                    .envs(self.command_environment())
+                    .envs(assigns)
+                    .current_dir(self.cur_dir.clone());
+                // Errors go to the command's own stderr, which the child takes.
+                let report = io.try_clone();
+                io.install(&mut proc);
+                let spawned = match &self.group {
+                    Some(group) => group.spawn(&mut proc),
+                    None => {
+                        jobs::restore_signals(&mut proc);
+                        proc.spawn()
+                    }
+                };
+                match spawned {
+                    Ok(child) => Ok(Running::Child(child)),
+                    Err(error) => {
+                        // Reported here so that it goes to the command's own stderr.
+                        let err = ShellError::Exec {
+                            command: words[0].to_string_lossy().into_owned(),
+                            error,
+                        };
+                        match report {
+                            Ok(io) => io.report(&err),
+                            Err(_) => eprintln!("{}", err),
+                        }
+                        Ok(Running::Done(err.status()))
+                    }
+                }
+            }
+            Stage::Subshell { ast, io } => {
+                let mut ctx = self.fork();
+                Ok(Running::Thread(spawn_scoped(scope, move || {
+                    ctx.run_subshell(ast, &io)
+                })?))
+            }
+            Stage::Compound { kind, io } => {
+                let mut ctx = self.fork();
+                Ok(Running::Thread(spawn_scoped(scope, move || {
+                    ctx.run_compound(kind, &io).unwrap_or_else(|e| {
+                        io.report(&e);
+                        e.status()
+                    })
+                })?))
+            }
+            Stage::Done(status) => Ok(Running::Done(status)),
         }
-        return None;
     }
 
-    fn pipe(&self, expr: &Expr, exec_type: ExecType) -> Option<Value> {
-        let left = self
-            .interpret_program(expr.left.as_ref().unwrap(), ExecType::Quiet)
-            .unwrap();
-        let right = self
-            .interpret_program(expr.right.as_ref().unwrap(), ExecType::DelayExec)
-            .unwrap();
-        let input = self.exec_cmds(left);
-        let out = self.pipe_cmds(right, input);
-        let out_string = String::from_utf8(out).unwrap();
-        if exec_type != ExecType::Quiet {
-            print!("{}", out_string);
+    /// Runs a pipeline, which gets a process group of its own and the terminal
+    /// under job control unless it is part of a job or of another pipeline.
+    fn run_pipeline(&mut self, cmds: &[Command], io: &Io) -> Result<i32, ShellError> {
+        if self.group.is_some() {
+            return self.run_stages(cmds, io);
         }
-        return Some(Value {
-            sym: Symbol::String,
-            val: out_string,
-            flags: Vec::new(),
-            args: Vec::new(),
-        });
+        self.group = Tracker::foreground();
+        let result = self.run_stages(cmds, io);
+        self.end_foreground();
+        result
     }
-    fn redir_left(&self, expr: &Expr, exec_type: ExecType) -> Option<Value> {
-        let left = self
-            .interpret_program(expr.left.as_ref().unwrap(), ExecType::DelayExec)
-            .unwrap();
-        let right = self
-            .interpret_program(expr.right.as_ref().unwrap(), ExecType::DelayExec)
-            .unwrap();
-        if right[0].sym != Symbol::File {
-            println!("{:#?}", right);
-            println!("Expected some type of file.");
-            return None;
+
+    /// Spawns every stage up front with each stdout connected to the next stdin
+    /// through an OS pipe, then waits on all of them. The status of a pipeline is
+    /// the status of its last stage. When a stage is stopped, its processes and
+    /// those after it are left to a stopped job.
+    fn run_stages(&mut self, cmds: &[Command], io: &Io) -> Result<i32, ShellError> {
+        let mut stages = Vec::new();
+        let mut prev: Option<OwnedFd> = None;
+        for (i, cmd) in cmds.iter().enumerate() {
+            let mut stage_io = match io.try_clone() {
+                Ok(stage_io) => stage_io,
+                Err(e) => {
+                    io.report(&ShellError::io("Error when duplicating file descriptor", e));
+                    stages.push(Stage::Done(1));
+                    break;
+                }
+            };
+            if let Some(reader) = prev.take() {
+                stage_io.set(0, reader);
+            }
+            if i + 1 < cmds.len() {
+                match pipe() {
+                    Ok((reader, writer)) => {
+                        stage_io.set(1, writer.into());
+                        prev = Some(reader.into());
+                    }
+                    Err(e) => {
+                        io.report(&ShellError::io("Error when creating pipe", e));
+                        stages.push(Stage::Done(1));
+                        break;
+                    }
+                }
+            }
+            stages.push(match self.prepare_command(cmd, stage_io) {
+                Ok(stage) => stage,
+                Err(e) if e.is_fatal() => return Err(e),
+                Err(e) => {
+                    io.report(&e);
+                    Stage::Done(e.status())
+                }
+            });
         }
-        let path = fs::metadata(&right[0].val);
-        if path.is_ok() {
-            let meta = path.unwrap();
-            if meta.is_file() {
-                let buf = fs::read_to_string(&right[0].val).expect("Failed to read file");
-                let output = self.pipe_cmds(left, buf.into_bytes().to_vec());
-                let out_string = String::from_utf8(output).unwrap();
+        // A lone function, builtin or compound command runs in the shell itself
+        // so that it can change its state.
+        if let [stage] = stages.as_mut_slice() {
+            match stage {
+                Stage::Simple { assigns, words, io } if self.is_function(words) => {
+                    return self.call_function(std::mem::take(assigns), &lossy(words), io);
+                }
+                Stage::Simple { assigns, words, io } if self.is_builtin(&lossy(words)) => {
+                    return Ok(self.run_builtin(std::mem::take(assigns), &lossy(words), io));
+                }
+                Stage::Compound { kind, io } => return self.run_compound(kind, io),
+                _ => {}
+            }
+        }
+        let ctx: &Self = self;
+        Ok(thread::scope(|scope| {
+            let running: Vec<_> = stages
+                .into_iter()
+                .map(|stage| ctx.spawn_command(scope, stage))
+                .collect();
+            // The processes started, along with the stage they belong to.
+            let pids: Vec<(usize, u32)> = running
+                .iter()
+                .enumerate()
+                .filter_map(|(i, stage)| match stage {
+                    Ok(Running::Child(child)) => Some((i, child.id())),
+                    _ => None,
+                })
+                .collect();
+            if let Some(group) = &ctx.group {
+                group.started(pids.last().map(|(_, pid)| *pid));
+            }
+            let mut status = 0;
+            for (i, stage) in running.into_iter().enumerate() {
+                status = match stage {
+                    Ok(Running::Child(child)) => {
+                        match jobs::wait_pid(child.id(), ctx.group.as_deref()) {
+                            Ok(Waited::Exited(status)) => status,
+                            Ok(Waited::Stopped(signal)) => {
+                                if let Some(group) = &ctx.group {
+                                    let rest = pids.iter().filter(|(j, _)| *j >= i);
+                                    ctx.stop_job(
+                                        group,
+                                        rest.map(|(_, pid)| *pid).collect(),
+                                        ctx.source_of(cmds),
+                                    );
+                                }
+                                status = 128 + signal;
+                                break;
+                            }
+                            Err(e) => {
+                                io.report(&ShellError::io("Error when waiting on command", e));
+                                1
+                            }
+                        }
+                    }
+                    Ok(Running::Thread(handle)) => handle.join().unwrap_or(1),
+                    Ok(Running::Done(status)) => status,
+                    Err(e) => {
+                        io.report(&e);
+                        e.status()
+                    }
+                };
+            }
+            status
+        }))
+    }
 
-                if exec_type == ExecType::Normal {
-                    print!("{}", out_string);
+    fn interpret_expression(&mut self, expr: &Expr, io: &Io) -> Result<i32, ShellError> {
+        match expr {
+            Expr::Pipeline(cmds) => {
+                if !self.traps.is_empty() {
+                    let command = String::from(self.source_of(cmds));
+                    self.debug_trap(&command, io)?;
+                }
+                let status = self.run_pipeline(cmds, io)?;
+                self.err_trap(status, io)?;
+                Ok(status)
+            }
+            Expr::Background { body, command } => self.spawn_job(body, command, io),
+            Expr::Binary {
+                symbol,
+                left,
+                right,
+            } => {
+                let testing = std::mem::replace(&mut self.testing, true);
+                let status = self.interpret_expression(left, io);
+                self.testing = testing;
+                self.last_status = status?;
+                if self.flow.is_some() {
+                    return Ok(self.last_status);
+                }
+                // `&&` only continues after success and `||` only after failure.
+                match (symbol, self.last_status) {
+                    (Symbol::AndIf, 0) => self.interpret_expression(right, io),
+                    (Symbol::OrIf, status) if status != 0 => self.interpret_expression(right, io),
+                    (_, status) => Ok(status),
                 }
-                return Some(Value {
-                    sym: Symbol::String,
-                    val: out_string,
-                    flags: Vec::new(),
-                    args: Vec::new(),
-                });
-            } else if meta.is_dir() {
-                println!("Expected some type of file, got directory.");
-                return None;
             }
-        } else {
-            println!("Error: No such file \"{}\"", &right[0].val);
         }
-        return None;
+    }
+    /// Runs every expression in `ast` and returns the exit status of the last one.
+    /// Fatal errors such as a failed expansion stop the program and are returned
+    /// for the caller to report.
+    pub fn interpret_program(&mut self, ast: &AST, io: &Io) -> Result<i32, ShellError> {
+        let outer = std::mem::replace(&mut self.source, ast.source.clone());
+        let result = self.interpret_exprs(&ast.exprs, io);
+        self.source = outer;
+        result
     }
 
-    fn double_redir_left(&self, expr: &Expr, exec_type: ExecType) -> Option<Value> {
-        let left = self
-            .interpret_program(expr.left.as_ref().unwrap(), ExecType::DelayExec)
-            .expect("Error when executing left");
-        let right = self
-            .interpret_program(expr.right.as_ref().unwrap(), ExecType::DelayExec)
-            .expect("Error when executing right");
-        let end_str = &right.clone()[0].val;
-        let mut input = String::new();
-        let mut line = String::new();
-        loop {
-            print!("heredoc >");
-            stdout().flush().expect("Failed to flush stdout");
-            stdin().read_line(&mut line).unwrap();
-            if line.strip_suffix("\n").unwrap() == end_str.as_str() {
+    fn interpret_exprs(&mut self, exprs: &[Expr], io: &Io) -> Result<i32, ShellError> {
+        for expr in exprs {
+            self.last_status = match self.interpret_expression(expr, io) {
+                Ok(status) => status,
+                Err(e) => {
+                    self.last_status = e.status();
+                    return Err(e);
+                }
+            };
+            self.run_signal_traps(io)?;
+            if self.flow.is_some() {
                 break;
             }
-            input += &line;
-            line.clear();
-        }
-        let output = self.pipe_cmds(left, input.as_bytes().to_vec());
-        let out_string = String::from_utf8(output).expect("Error converting output to utf-8.");
-        if exec_type == ExecType::Normal {
-            print!("{}", out_string);
         }
-        return Some(Value {
-            sym: Symbol::String,
-            val: out_string,
-            flags: Vec::new(),
-            args: Vec::new(),
-        });
+        Ok(self.last_status)
     }
-    fn interpret_expression(&self, expr: &Expr, exec_type: ExecType) -> Option<Value> {
-        match expr.kind {
-            Kind::Expr => {
-                match expr.symbol {
-                    Symbol::None => {
-                        if expr.left.is_none() {
-                            return None;
-                        }
-                        let astleft;
-                        if exec_type == ExecType::DelayExec {
-                            astleft =
-                                self.interpret_program(expr.left.as_ref().unwrap(), exec_type);
-                        } else {
-                            astleft =
-                                self.interpret_program(expr.left.as_ref().unwrap(), exec_type);
-                        }
-                        if astleft.is_none() {
-                            return None;
+
+    /// Runs a compound command in the shell itself, or stores the function it
+    /// defines.
+    fn run_compound(&mut self, kind: &CommandKind, io: &Io) -> Result<i32, ShellError> {
+        match kind {
+            CommandKind::If {
+                branches,
+                otherwise,
+            } => {
+                for (cond, body) in branches {
+                    let status = self.run_test(cond, io)?;
+                    if self.flow.is_some() {
+                        return Ok(status);
+                    }
+                    if status == 0 {
+                        return self.interpret_program(body, io);
+                    }
+                }
+                match otherwise {
+                    Some(body) => self.interpret_program(body, io),
+                    // An `if` without a matching branch succeeds.
+                    None => Ok(0),
+                }
+            }
+            CommandKind::Loop { until, cond, body } => {
+                self.loop_depth += 1;
+                let mut status = Ok(0);
+                loop {
+                    let cond_status = match self.run_test(cond, io) {
+                        Ok(cond_status) => cond_status,
+                        Err(e) => {
+                            status = Err(e);
+                            break;
                         }
-                        let left = astleft.unwrap();
-                        if exec_type == ExecType::DelayExec {
-                            if left.len() > 0 {
-                                return Some(Value {
-                                    sym: left[0].sym,
-                                    val: left[0].val.clone(),
-                                    flags: left[0].flags.clone(),
-                                    args: left[0].args.clone(),
-                                });
-                            }
-                        } else if left.len() > 0 {
-                            let out_string = left
-                                .iter()
-                                .fold(String::from(""), |cur, next| cur + &next.val);
-                            return Some(Value {
-                                sym: Symbol::String,
-                                val: out_string,
-                                flags: Vec::new(),
-                                args: Vec::new(),
-                            });
-                        } else {
-                            //println!("ERROR! {:#?}",left);
+                    };
+                    if self.leave_iteration() || (cond_status == 0) == *until {
+                        break;
+                    }
+                    status = self.interpret_program(body, io);
+                    if status.is_err() || self.leave_iteration() {
+                        break;
+                    }
+                }
+                self.loop_depth -= 1;
+                status
+            }
+            CommandKind::For { name, words, body } => {
+                let items = match words {
+                    Some(words) => {
+                        let mut items = Vec::new();
+                        for word in words {
+                            items.extend(lossy(&self.expand_word(word)?));
                         }
+                        items
                     }
-                    Symbol::RedirRight | Symbol::DoubleRedirRight => {
-                        return self.redir_right(expr);
+                    None => self.args.get(1..).unwrap_or_default().to_vec(),
+                };
+                self.loop_depth += 1;
+                let mut status = Ok(0);
+                for item in items {
+                    self.set_var(name, item);
+                    status = self.interpret_program(body, io);
+                    if status.is_err() || self.leave_iteration() {
+                        break;
                     }
-                    Symbol::Pipe => {
-                        return self.pipe(expr, exec_type);
+                }
+                self.loop_depth -= 1;
+                status
+            }
+            CommandKind::Case { word, arms } => {
+                let text = self.expand_string(word)?;
+                let mut status = 0;
+                // Set once an arm matched and its operator decides whether the
+                // next arm runs without testing its patterns.
+                let mut fall_through = false;
+                for arm in arms {
+                    if !fall_through {
+                        let mut matched = false;
+                        for pattern in &arm.patterns {
+                            if glob::matches(&self.expand_pattern(pattern)?, &text) {
+                                matched = true;
+                                break;
+                            }
+                        }
+                        if !matched {
+                            continue;
+                        }
                     }
-                    Symbol::RedirLeft => {
-                        return self.redir_left(expr, exec_type);
+                    status = self.interpret_program(&arm.body, io)?;
+                    if self.flow.is_some() || arm.end == CaseEnd::Break {
+                        break;
                     }
-                    Symbol::DoubleRedirLeft => {
-                        return self.double_redir_left(expr, exec_type);
+                    fall_through = arm.end == CaseEnd::FallThrough;
+                }
+                Ok(status)
+            }
+            CommandKind::Group(ast) => self.interpret_program(ast, io),
+            CommandKind::Cond(cond) => match self.eval_cond(cond, io) {
+                Ok(value) => Ok(!value as i32),
+                Err(e) if e.is_fatal() => Err(e),
+                Err(e) => {
+                    io.report(&e);
+                    Ok(e.status())
+                }
+            },
+            CommandKind::Arithmetic(expr) => {
+                let expr = self.expand_string(expr)?;
+                // Only failed expansions are fatal, a bad expression fails the
+                // command like a failed test does.
+                match self.arithmetic(&expr) {
+                    Ok(value) => Ok((value == 0) as i32),
+                    Err(e) => {
+                        io.report(&e);
+                        Ok(1)
                     }
-                    _ => {}
                 }
             }
-            Kind::Value => {
-                if exec_type == ExecType::DelayExec || expr.symbol != Symbol::Cmd {
-                    return Some(Value {
-                        sym: expr.symbol,
-                        val: String::from(expr.value.as_ref().unwrap().as_str()),
-                        flags: expr.flags.clone(),
-                        args: expr.args.clone(),
-                    });
-                } else {
-                    //This should be a default pipe that
-                    //is the same as a normal terminal
-                    let mut proc: Result<Child, Error>;
-                    //pipe it and read the output so our program can handle it
-                    if exec_type == ExecType::Quiet {
-                        proc = Command::new(expr.value.as_ref().unwrap())
-                            .args(expr.flags.clone())
-                            .args(expr.args.clone())
-                            .current_dir(self.cur_dir.clone())
-                            .stdout(Stdio::piped())
-                            .spawn();
-                    } else {
+            CommandKind::Function { name, body } => {
+                self.functions.insert(name.clone(), body.clone());
+                Ok(0)
+            }
+            CommandKind::Simple { .. } | CommandKind::Subshell(_) => Ok(0),
+        }
+    }
 
-                        proc = Command::new(expr.value.as_ref().unwrap())
-                            .args(expr.flags.clone())
-                            .args(expr.args.clone())
-                            .current_dir(self.cur_dir.clone())
-                            .spawn();
-                    }
+    /// Runs the condition of an `if`, `while` or `until`.
+    fn run_test(&mut self, cond: &AST, io: &Io) -> Result<i32, ShellError> {
+        let testing = std::mem::replace(&mut self.testing, true);
+        let result = self.interpret_program(cond, io);
+        self.testing = testing;
+        result
+    }
 
-                    if proc.is_ok() {
-                        let child = proc.unwrap();
-                        let output = child
-                            .wait_with_output()
-                            .expect("Error occured when parsing output")
-                            .stdout;
-                        return Some(Value {
-                            sym: Symbol::String,
-                            val: String::from_utf8(output).unwrap(),
-                            flags: Vec::new(),
-                            args: Vec::new(),
-                        });
-                    } else {
-                        println!(
-                            "Error when executing command \"{}\"\n {:#?}",
-                            expr.value.as_ref().unwrap(),
-                            proc
-                        );
-                    }
-                }
+    /// Handles a `break` or `continue` at the end of an iteration of the
+    /// innermost loop, returning whether the loop has to stop.
+    fn leave_iteration(&mut self) -> bool {
+        match self.flow {
+            None => false,
+            Some(Flow::Continue(1)) => {
+                self.flow = None;
+                false
+            }
+            Some(Flow::Break(1)) => {
+                self.flow = None;
+                true
             }
+            // Outer loops are left with one level less to go.
+            Some(Flow::Break(n)) => {
+                self.flow = Some(Flow::Break(n - 1));
+                true
+            }
+            Some(Flow::Continue(n)) => {
+                self.flow = Some(Flow::Continue(n - 1));
+                true
+            }
+            Some(Flow::Return(_) | Flow::Exit(_)) => true,
         }
-        return None;
     }
-    pub fn interpret_program(&self, ast: &AST, exec_type: ExecType) -> Option<Vec<Value>> {
-        let mut output = Vec::new();
-        ast.exprs.iter().for_each(|expr: &Expr| {
-            let out_cur = self.interpret_expression(expr, exec_type);
-            if !out_cur.is_none() {
-                output.push(out_cur.unwrap());
+
+    /// Runs the function named by `words[0]` with the rest of `words` as its
+    /// positional parameters. Assignments written before the call only last
+    /// until it returns, like variables made local with `local`.
+    fn call_function(
+        &mut self,
+        assigns: Vec<(String, String)>,
+        words: &[String],
+        io: &Io,
+    ) -> Result<i32, ShellError> {
+        let body = self.functions[&words[0]].clone();
+        let limit = self
+            .get_var("FUNCNEST")
+            .and_then(|n| n.parse().ok())
+            .filter(|n| *n > 0)
+            .unwrap_or(MAX_CALL_DEPTH);
+        if self.locals.len() >= limit {
+            return Err(ShellError::Nesting {
+                function: words[0].clone(),
+                limit,
+            });
+        }
+        let mut args = vec![self.args[0].clone()];
+        args.extend_from_slice(&words[1..]);
+        let args = std::mem::replace(&mut self.args, args);
+        // Loops around the call cannot be left from inside the function.
+        let loop_depth = std::mem::take(&mut self.loop_depth);
+        self.locals.push(HashMap::new());
+        for (name, value) in assigns {
+            self.make_local(&name);
+            self.set_var(&name, value);
+        }
+        let result = self.interpret_program(&body, io);
+        for (name, value) in self.locals.pop().unwrap_or_default() {
+            match value {
+                Some(value) => self.set_var(&name, value),
+                None => {
+                    self.vars.remove(&name);
+                }
+            }
+        }
+        self.loop_depth = loop_depth;
+        self.args = args;
+        match self.flow {
+            Some(Flow::Return(status)) => {
+                self.flow = None;
+                Ok(status)
             }
+            _ => result,
+        }
+    }
+
+    /// Saves the value of `name` so that it is restored when the function being
+    /// run returns. Returns `false` outside of functions.
+    pub fn make_local(&mut self, name: &str) -> bool {
+        let Some(frame) = self.locals.last_mut() else {
+            return false;
+        };
+        if !frame.contains_key(name) {
+            frame.insert(String::from(name), self.vars.get(name).cloned());
+        }
+        true
+    }
+
+    /// Runs `ast` like a subshell, where a fatal error only stops the subshell
+    /// and is reported on its stderr. It starts without the traps of the shell
+    /// and runs its own `EXIT` trap when it is done.
+    pub fn run_subshell(&mut self, ast: &AST, io: &Io) -> i32 {
+        self.reset_traps();
+        let status = self.interpret_program(ast, io).unwrap_or_else(|e| {
+            io.report(&e);
+            e.status()
         });
-        return Some(output);
+        self.exit_trap(status, io)
     }
 }
@@ -0,0 +1,629 @@
+use crate::ast::AST;
+use crate::error::ShellError;
+use crate::interpreter::{ExecContext, Io, STACK_SIZE};
+use std::fs::File;
+use std::io::{self, IsTerminal, Write};
+use std::os::fd::{AsFd, AsRawFd, OwnedFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, OnceLock};
+use std::thread;
+
+/// The terminal of an interactive shell, which is handed to the process group
+/// of the job running in the foreground. Only set while job control is on.
+static TERMINAL: OnceLock<OwnedFd> = OnceLock::new();
+
+/// Signals sent from the terminal, which an interactive shell ignores and the
+/// commands it starts get back.
+const TERMINAL_SIGNALS: [i32; 5] = [
+    libc::SIGINT,
+    libc::SIGQUIT,
+    libc::SIGTSTP,
+    libc::SIGTTIN,
+    libc::SIGTTOU,
+];
+
+/// Turns on job control when stdin is a terminal: the shell gets a process group
+/// of its own that owns the terminal, and ignores the signals typed on it so
+/// that Ctrl-C and Ctrl-Z only reach the job in the foreground.
+pub fn enable_job_control() {
+    let stdin = io::stdin();
+    if !stdin.is_terminal() {
+        return;
+    }
+    let Ok(terminal) = stdin.as_fd().try_clone_to_owned() else {
+        return;
+    };
+    // SAFETY: plain calls on the shell's own process and descriptors.
+    unsafe {
+        for signal in TERMINAL_SIGNALS {
+            libc::signal(signal, libc::SIG_IGN);
+        }
+        // Fails for a session leader, which already leads its process group.
+        libc::setpgid(0, 0);
+        libc::tcsetpgrp(terminal.as_raw_fd(), libc::getpgrp());
+    }
+    let _ = TERMINAL.set(terminal);
+}
+
+/// Hands the terminal to process group `pgid`, if job control is on.
+fn give_terminal(pgid: i32) {
+    if let Some(terminal) = TERMINAL.get() {
+        // SAFETY: `terminal` is open for as long as the shell runs.
+        unsafe { libc::tcsetpgrp(terminal.as_raw_fd(), pgid) };
+    }
+}
+
+/// Takes the terminal back for the shell once the foreground job has finished
+/// or stopped.
+fn reclaim_terminal() {
+    // SAFETY: `getpgrp` cannot fail.
+    give_terminal(unsafe { libc::getpgrp() });
+}
+
+/// Whether the shell ignores `signal` because job control is on, which is what
+/// it goes back to when a trap on it is reset.
+pub fn ignores(signal: i32) -> bool {
+    TERMINAL.get().is_some() && TERMINAL_SIGNALS.contains(&signal)
+}
+
+/// Sends `signal` to every process of group `pgid`.
+fn signal_group(pgid: i32, signal: i32) -> io::Result<()> {
+    // SAFETY: a plain system call.
+    match unsafe { libc::kill(-pgid, signal) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// How a process that was waited on stopped running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Waited {
+    /// It exited with the given shell status, `128+N` when killed by signal N.
+    Exited(i32),
+    /// It was stopped by signal N, as with Ctrl-Z.
+    Stopped(i32),
+}
+
+/// How far a job has got in starting its first pipeline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Progress {
+    Starting,
+    /// The pipeline was started, along with the pid of its last process if it
+    /// has one.
+    Started(Option<u32>),
+    Finished,
+}
+
+#[derive(Debug)]
+struct State {
+    progress: Progress,
+    /// The process group the job's processes are put in, made by the first one.
+    pgid: Option<i32>,
+    /// Whether the job's processes get the terminal.
+    foreground: bool,
+    stopped: bool,
+    finished: bool,
+}
+
+/// What a job shares with the threads that start and wait for its processes.
+/// A pipeline run in the foreground of an interactive shell has one too, so
+/// that its processes share a process group.
+#[derive(Debug)]
+pub struct Tracker {
+    state: Mutex<State>,
+    changed: Condvar,
+    /// Whether the job runs on threads of its own, which keep waiting while its
+    /// processes are stopped instead of handing them over to a new job.
+    background: bool,
+}
+
+impl Tracker {
+    fn new(background: bool, foreground: bool) -> Self {
+        Self {
+            state: Mutex::new(State {
+                progress: Progress::Starting,
+                pgid: None,
+                foreground,
+                stopped: false,
+                finished: false,
+            }),
+            changed: Condvar::new(),
+            background,
+        }
+    }
+    /// A tracker for a pipeline run in the foreground of an interactive shell.
+    pub fn foreground() -> Option<Arc<Self>> {
+        TERMINAL.get().map(|_| Arc::new(Self::new(false, true)))
+    }
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    fn update(&self, f: impl FnOnce(&mut State)) {
+        f(&mut self.lock());
+        self.changed.notify_all();
+    }
+    /// Records that the job started a pipeline ending in process `pid`, only
+    /// the first pipeline counts.
+    pub fn started(&self, pid: Option<u32>) {
+        self.update(|state| {
+            if state.progress == Progress::Starting {
+                state.progress = Progress::Started(pid);
+            }
+        });
+    }
+    fn finish(&self) {
+        self.update(|state| {
+            if state.progress == Progress::Starting {
+                state.progress = Progress::Finished;
+            }
+            state.finished = true;
+        });
+    }
+    fn set_stopped(&self, stopped: bool) {
+        self.update(|state| state.stopped = stopped);
+    }
+    fn is_stopped(&self) -> bool {
+        self.lock().stopped
+    }
+    fn pgid(&self) -> Option<i32> {
+        self.lock().pgid
+    }
+    /// Waits until the job started its first pipeline, returning the pid of the
+    /// pipeline's last process. Jobs that only run builtins have no pid.
+    pub fn pid(&self) -> Option<u32> {
+        let mut state = self.lock();
+        while state.progress == Progress::Starting {
+            state = self.changed.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+        match state.progress {
+            Progress::Started(pid) => pid,
+            _ => None,
+        }
+    }
+    /// Waits until the job either finishes or stops, returning whether it
+    /// stopped.
+    fn wait_for_change(&self) -> bool {
+        let mut state = self.lock();
+        while !state.finished && !state.stopped {
+            state = self.changed.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+        !state.finished
+    }
+
+    /// Spawns `proc` into the job's process group, starting a new group when
+    /// there is none yet or the old one is gone because all of its processes
+    /// exited. The group gets the terminal when the job is in the foreground.
+    pub fn spawn(&self, proc: &mut Command) -> io::Result<Child> {
+        let mut state = self.lock();
+        if let Some(terminal) = TERMINAL.get().filter(|_| state.foreground) {
+            let terminal = terminal.as_raw_fd();
+            // Done in the child as well, so that it cannot read from the
+            // terminal before the shell got around to handing it over.
+            // SAFETY: `tcsetpgrp` and `getpgrp` are async-signal-safe.
+            unsafe {
+                proc.pre_exec(move || {
+                    libc::tcsetpgrp(terminal, libc::getpgrp());
+                    Ok(())
+                })
+            };
+        }
+        restore_signals(proc);
+        let child = match state.pgid {
+            Some(pgid) => match proc.process_group(pgid).spawn() {
+                Err(e) if e.raw_os_error() == Some(libc::EPERM) => {
+                    state.pgid = None;
+                    proc.process_group(0).spawn()
+                }
+                result => result,
+            },
+            None => proc.process_group(0).spawn(),
+        }?;
+        let pgid = *state.pgid.get_or_insert(child.id() as i32);
+        if state.foreground {
+            give_terminal(pgid);
+        }
+        Ok(child)
+    }
+}
+
+/// Gives the commands started by an interactive shell the default handling of
+/// the signals the shell ignores, which they would otherwise inherit.
+pub fn restore_signals(proc: &mut Command) {
+    if TERMINAL.get().is_none() {
+        return;
+    }
+    // SAFETY: `signal` is async-signal-safe.
+    unsafe {
+        proc.pre_exec(|| {
+            for signal in TERMINAL_SIGNALS {
+                libc::signal(signal, libc::SIG_DFL);
+            }
+            Ok(())
+        })
+    };
+}
+
+/// Waits for process `pid` to exit. It only returns early when the process
+/// stops while run by a foreground pipeline, a job's own threads keep waiting
+/// and mark the job as stopped in the meantime.
+pub fn wait_pid(pid: u32, tracker: Option<&Tracker>) -> io::Result<Waited> {
+    loop {
+        let mut status = 0;
+        // SAFETY: `status` outlives the call.
+        let waited =
+            unsafe { libc::waitpid(pid as i32, &mut status, libc::WUNTRACED | libc::WCONTINUED) };
+        if waited < 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(error);
+        }
+        if libc::WIFCONTINUED(status) {
+            if let Some(tracker) = tracker {
+                tracker.set_stopped(false);
+            }
+            continue;
+        }
+        if libc::WIFSTOPPED(status) {
+            match tracker {
+                Some(tracker) if tracker.background => tracker.set_stopped(true),
+                Some(_) => return Ok(Waited::Stopped(libc::WSTOPSIG(status))),
+                None => {}
+            }
+            continue;
+        }
+        return Ok(Waited::Exited(match libc::WIFSIGNALED(status) {
+            true => 128 + libc::WTERMSIG(status),
+            false => libc::WEXITSTATUS(status),
+        }));
+    }
+}
+
+/// A pipeline or list started with `&`, or the rest of a foreground pipeline
+/// that was stopped, run and waited for by a thread of its own.
+#[derive(Debug)]
+pub struct Job {
+    /// The number that `%N` refers to.
+    pub id: usize,
+    /// The source the job was started from, without the `&`.
+    pub command: String,
+    tracker: Arc<Tracker>,
+    thread: Option<thread::JoinHandle<i32>>,
+    /// The exit status, once the thread has been joined.
+    status: Option<i32>,
+}
+
+impl Job {
+    fn is_done(&self) -> bool {
+        self.status.is_some() || self.thread.as_ref().is_none_or(|t| t.is_finished())
+    }
+    /// Waits for the job to finish and returns its exit status.
+    fn wait(&mut self) -> i32 {
+        if let Some(thread) = self.thread.take() {
+            self.status = Some(thread.join().unwrap_or(1));
+        }
+        self.status.unwrap_or(1)
+    }
+    /// The state shown by `jobs`, as in `Running` or `Exit 2`.
+    fn state(&self) -> String {
+        match (self.is_done(), self.status) {
+            (false, _) if self.tracker.is_stopped() => String::from("Stopped"),
+            (false, _) => String::from("Running"),
+            (true, Some(0) | None) => String::from("Done"),
+            (true, Some(status)) => format!("Exit {}", status),
+        }
+    }
+    /// A line describing the job, marked `+` when it is the current job and `-`
+    /// when it is the previous one.
+    fn describe(&self, mark: char, pid: bool) -> String {
+        let pid = match (pid, self.tracker.pid()) {
+            (true, Some(pid)) => format!("{} ", pid),
+            _ => String::new(),
+        };
+        let state = self.state();
+        let background = if state == "Running" { " &" } else { "" };
+        format!(
+            "[{}]{}  {}{:<24}{}{}",
+            self.id, mark, pid, state, self.command, background
+        )
+    }
+    /// Lets the job's processes go on after they were stopped.
+    fn resume(&self) {
+        if let Some(pgid) = self.tracker.pgid() {
+            let _ = signal_group(pgid, libc::SIGCONT);
+        }
+        self.tracker.set_stopped(false);
+    }
+}
+
+/// The `+` or `-` mark of the job at `index` in a table of `len` jobs, where the
+/// latest job is the current one.
+fn mark(index: usize, len: usize) -> char {
+    match len - index {
+        1 => '+',
+        2 => '-',
+        _ => ' ',
+    }
+}
+
+/// Finds the index of the job that `spec` names: `%N`, `%%` or `%+` for the
+/// current job, `%-` for the previous one, `%name` for the latest job whose
+/// command starts with `name`, or the pid of a job's last process. Without a
+/// spec it is the current job.
+fn find_job(jobs: &[Job], spec: Option<&String>) -> Result<usize, String> {
+    let Some(spec) = spec else {
+        return jobs
+            .len()
+            .checked_sub(1)
+            .ok_or_else(|| String::from("current: no such job"));
+    };
+    let found = match spec.strip_prefix('%') {
+        Some("%" | "+" | "") => jobs.len().checked_sub(1),
+        Some("-") => jobs.len().checked_sub(2),
+        Some(n) if n.chars().all(|c| c.is_ascii_digit()) => {
+            jobs.iter().position(|job| n.parse() == Ok(job.id))
+        }
+        Some(name) => jobs.iter().rposition(|job| job.command.starts_with(name)),
+        None => {
+            let pid = spec
+                .parse::<u32>()
+                .map_err(|_| format!("{}: not a pid or valid job spec", spec))?;
+            return jobs
+                .iter()
+                .position(|job| job.tracker.pid() == Some(pid))
+                .ok_or_else(|| format!("pid {} is not a child of this shell", pid));
+        }
+    };
+    found.ok_or_else(|| format!("{}: no such job", spec))
+}
+
+impl ExecContext {
+    /// Adds a job run by `thread` to the table and returns its number.
+    fn add_job(
+        &self,
+        command: &str,
+        tracker: Arc<Tracker>,
+        thread: thread::JoinHandle<i32>,
+    ) -> usize {
+        let mut jobs = self.jobs.lock().unwrap_or_else(|e| e.into_inner());
+        let id = jobs.last().map_or(1, |job| job.id + 1);
+        jobs.push(Job {
+            id,
+            command: String::from(command),
+            tracker,
+            thread: Some(thread),
+            status: None,
+        });
+        id
+    }
+
+    /// Puts `job` back in the table, in order of its number.
+    fn restore_job(&self, job: Job) {
+        let mut jobs = self.jobs.lock().unwrap_or_else(|e| e.into_inner());
+        let index = jobs.partition_point(|other| other.id < job.id);
+        jobs.insert(index, job);
+    }
+
+    /// Starts `body` in the background as job `command` and returns without
+    /// waiting for it. Without job control its stdin is `/dev/null` unless it
+    /// is redirected.
+    pub fn spawn_job(
+        &mut self,
+        body: &Arc<AST>,
+        command: &str,
+        io: &Io,
+    ) -> Result<i32, ShellError> {
+        let mut io = io
+            .try_clone()
+            .map_err(|e| ShellError::io("Error when duplicating file descriptor", e))?;
+        if TERMINAL.get().is_none() {
+            if let Ok(null) = File::open("/dev/null") {
+                io.set(0, null.into());
+            }
+        }
+        let tracker = Arc::new(Tracker::new(true, false));
+        let mut ctx = self.clone();
+        ctx.jobs = Arc::default();
+        ctx.group = Some(tracker.clone());
+        let body = body.clone();
+        let thread_tracker = tracker.clone();
+        let thread = thread::Builder::new()
+            .stack_size(STACK_SIZE)
+            .spawn(move || {
+                let status = ctx.run_subshell(&body, &io);
+                thread_tracker.finish();
+                status
+            })
+            .map_err(|e| ShellError::io("Error when starting background job", e))?;
+        self.add_job(command, tracker.clone(), thread);
+        self.last_job = Some(tracker);
+        Ok(0)
+    }
+
+    /// Turns the processes `pids` of a foreground pipeline that were stopped into
+    /// a stopped job, which a thread goes on waiting for. The pipeline's later
+    /// processes get a new process group.
+    pub fn stop_job(&self, tracker: &Tracker, pids: Vec<u32>, command: &str) {
+        let job_tracker = Arc::new(Tracker::new(true, false));
+        job_tracker.update(|state| {
+            state.pgid = tracker.pgid();
+            state.stopped = true;
+        });
+        job_tracker.started(pids.last().copied());
+        tracker.update(|state| state.pgid = None);
+        let waiter = job_tracker.clone();
+        let thread = thread::spawn(move || {
+            let mut status = 0;
+            for pid in pids {
+                status = match wait_pid(pid, Some(&waiter)) {
+                    Ok(Waited::Exited(status) | Waited::Stopped(status)) => status,
+                    Err(_) => 1,
+                };
+            }
+            waiter.finish();
+            status
+        });
+        reclaim_terminal();
+        let id = self.add_job(command, job_tracker, thread);
+        eprintln!("\n[{}]+  {:<24}{}", id, "Stopped", command);
+    }
+
+    /// Takes the terminal back after a foreground pipeline, if it was given
+    /// away.
+    pub fn end_foreground(&mut self) {
+        if self.group.take().is_some() {
+            reclaim_terminal();
+        }
+    }
+
+    /// The pid of the last process of the latest background job, as `$!`.
+    pub fn last_job_pid(&self) -> Option<u32> {
+        self.last_job.as_ref()?.pid()
+    }
+
+    /// Sends `signal` to the processes of the job that `spec` names. A stopped
+    /// job is continued after `SIGTERM` or `SIGHUP` so that it gets to act on
+    /// them.
+    pub fn signal_job(&self, spec: &String, signal: i32) -> Result<(), String> {
+        let jobs = self.jobs.lock().unwrap_or_else(|e| e.into_inner());
+        let job = &jobs[find_job(&jobs, Some(spec))?];
+        // The job's process group is made by its first process.
+        job.tracker.pid();
+        let Some(pgid) = job.tracker.pgid() else {
+            return Err(format!("{}: job has no processes", spec));
+        };
+        signal_group(pgid, signal).map_err(|e| format!("{}: {}", spec, e))?;
+        if job.tracker.is_stopped() && matches!(signal, libc::SIGTERM | libc::SIGHUP) {
+            job.resume();
+        }
+        Ok(())
+    }
+
+    /// Takes the job that `spec` names, or the current job, out of the table.
+    fn take_job(&self, spec: Option<&String>) -> Result<Job, String> {
+        let mut jobs = self.jobs.lock().unwrap_or_else(|e| e.into_inner());
+        let index = find_job(&jobs, spec)?;
+        Ok(jobs.remove(index))
+    }
+
+    /// Prints a notice for every background job that has finished and removes
+    /// it from the table, as done before each prompt.
+    pub fn notify_jobs(&mut self) {
+        let mut jobs = self.jobs.lock().unwrap_or_else(|e| e.into_inner());
+        let len = jobs.len();
+        let mut index = 0;
+        for i in 0..len {
+            if jobs[index].is_done() {
+                let mut job = jobs.remove(index);
+                job.wait();
+                eprintln!("{}", job.describe(mark(i, len), false));
+            } else {
+                index += 1;
+            }
+        }
+    }
+
+    /// `jobs [-l|-p]`, `-l` also shows pids and `-p` only shows pids. Jobs shown
+    /// as finished are removed from the table.
+    pub fn jobs(&mut self, args: &[String], out: &mut dyn Write) -> io::Result<i32> {
+        let mut jobs = self.jobs.lock().unwrap_or_else(|e| e.into_inner());
+        let len = jobs.len();
+        for (i, job) in jobs.iter_mut().enumerate() {
+            if job.is_done() {
+                job.wait();
+            }
+            match args.first().map(String::as_str) {
+                Some("-p") => {
+                    if let Some(pid) = job.tracker.pid() {
+                        writeln!(out, "{}", pid)?;
+                    }
+                }
+                Some("-l") => writeln!(out, "{}", job.describe(mark(i, len), true))?,
+                _ => writeln!(out, "{}", job.describe(mark(i, len), false))?,
+            }
+        }
+        jobs.retain(|job| job.status.is_none());
+        Ok(0)
+    }
+
+    /// `fg [job]`, brings a job to the foreground, resuming it if it was
+    /// stopped, and waits for it to finish or stop again.
+    pub fn fg(
+        &mut self,
+        args: &[String],
+        out: &mut dyn Write,
+        err: &mut dyn Write,
+    ) -> io::Result<i32> {
+        let mut job = match self.take_job(args.first()) {
+            Ok(job) => job,
+            Err(e) => {
+                writeln!(err, "fg: {}", e)?;
+                return Ok(1);
+            }
+        };
+        writeln!(out, "{}", job.command)?;
+        if let Some(pgid) = job.tracker.pgid() {
+            give_terminal(pgid);
+        }
+        job.tracker.update(|state| state.foreground = true);
+        job.resume();
+        let stopped = job.tracker.wait_for_change();
+        job.tracker.update(|state| state.foreground = false);
+        reclaim_terminal();
+        if !stopped {
+            return Ok(job.wait());
+        }
+        writeln!(err, "\n[{}]+  {:<24}{}", job.id, "Stopped", job.command)?;
+        self.restore_job(job);
+        Ok(128 + libc::SIGTSTP)
+    }
+
+    /// `bg [job]`, lets a stopped job go on running in the background.
+    pub fn bg(
+        &mut self,
+        args: &[String],
+        out: &mut dyn Write,
+        err: &mut dyn Write,
+    ) -> io::Result<i32> {
+        let jobs = self.jobs.lock().unwrap_or_else(|e| e.into_inner());
+        let job = match find_job(&jobs, args.first()) {
+            Ok(index) => &jobs[index],
+            Err(e) => {
+                writeln!(err, "bg: {}", e)?;
+                return Ok(1);
+            }
+        };
+        if !job.tracker.is_stopped() {
+            writeln!(err, "bg: job {} already in background", job.id)?;
+            return Ok(0);
+        }
+        job.resume();
+        writeln!(out, "[{}]+ {} &", job.id, job.command)?;
+        Ok(0)
+    }
+
+    /// `wait [job|pid...]`, waits for the given jobs and returns the status of
+    /// the last one, or for every job when none is given.
+    pub fn wait(&mut self, args: &[String], err: &mut dyn Write) -> io::Result<i32> {
+        if args.is_empty() {
+            let jobs = std::mem::take(&mut *self.jobs.lock().unwrap_or_else(|e| e.into_inner()));
+            for mut job in jobs {
+                job.wait();
+            }
+            return Ok(0);
+        }
+        let mut status = 0;
+        for spec in args {
+            status = match self.take_job(Some(spec)) {
+                Ok(mut job) => job.wait(),
+                Err(e) => {
+                    writeln!(err, "wait: {}", e)?;
+                    127
+                }
+            };
+        }
+        Ok(status)
+    }
+}
@@ -1,104 +1,163 @@
+mod arith;
 mod ast;
+mod builtins;
+mod editor;
 mod error;
+mod expand;
+mod glob;
 mod interpreter;
+mod jobs;
 mod parser;
 mod scanner;
+mod signals;
+mod test;
 mod token;
+use editor::Editor;
 use scanner::Scanner;
-use std::env::current_dir;
-use std::io::{stdin, stdout, Write};
-use std::path::Path;
+use std::env::{self, current_dir};
+use std::fs;
+use std::io::{stdin, IsTerminal, Read};
 use std::process;
 use std::thread;
 
-use crate::{interpreter::ExecContext, parser::parse_program};
-fn main() {
-    run_prompt();
-}
+use crate::error::ShellError;
+use crate::interpreter::{ExecContext, Flow, Io, STACK_SIZE};
+use crate::parser::parse_program;
 
-fn run(source: &str, exec: ExecContext) {
-    let mut lexer = Scanner::new(source.to_string());
-    lexer.scan_tokens();
-    let ast = parse_program(lexer).unwrap();
-    exec.interpret_program(&ast, interpreter::ExecType::Normal);
-}
+const USAGE: &str = "usage: what-the-shell [-c command [name [arg...]] | script [arg...]]";
 
-fn eval_path_str(path_str: &str) -> String {
-    let mut strs: Vec<&str> = path_str.split("/").collect();
-    let mut i = 0;
-    while i < strs.len() {
-        if strs[i] == ".." {
-            if i > 0 {
-                strs.remove(i - 1);
-                strs.remove(i - 1);
-                i -= 1;
-            } else {
-                strs.remove(i);
-            }
-        } else if strs[i].is_empty() {
-            strs.remove(i);
-        } else {
-            i += 1;
-        }
-    }
-    let mut path = strs
-        .iter()
-        .fold(String::new(), |cur, next| cur.to_owned() + "/" + next);
-    if path.is_empty() {
-        path = String::from("/");
-    }
-    return path;
+fn main() {
+    // Signals are blocked before the shell thread exists, so none can reach the
+    // main thread while it only waits for the shell.
+    signals::block_signals();
+    // Deep recursion in shell functions needs more stack than the main thread has.
+    let shell = thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(shell)
+        .expect("failed to spawn thread");
+    process::exit(shell.join().unwrap_or(1));
 }
-fn check_special(mut line: String, exec: &mut ExecContext) -> bool {
-    line.pop();
-    let lines: Vec<&str> = line.split(" ").collect();
-    match lines[0] {
-        "exit" => {
-            process::exit(0);
+
+/// Runs the prompt, a script file, a `-c` command string, or the commands piped
+/// on stdin, returning the status of the last command.
+fn shell() -> i32 {
+    signals::unblock_signals();
+    let argv: Vec<String> = env::args_os()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect();
+    let shell = argv
+        .first()
+        .cloned()
+        .unwrap_or_else(|| String::from("what-the-shell"));
+    let current_dir = current_dir().unwrap_or_else(|e| panic!("failed to get current dir: {}", e));
+    let new_context = |args: Vec<String>| ExecContext::new(&current_dir, args);
+    match argv.get(1).map(String::as_str) {
+        Some("-c") => {
+            let Some(source) = argv.get(2) else {
+                eprintln!("{}: -c: option requires an argument\n{}", shell, USAGE);
+                process::exit(2);
+            };
+            // The word after the command becomes `$0`, the rest `$1`, `$2`, ...
+            let args = match argv.len() > 3 {
+                true => argv[3..].to_vec(),
+                false => vec![shell],
+            };
+            run_script(source.clone(), new_context(args))
         }
-        "cd" => {
-            let mut path_str = String::new();
-            if !lines[1].starts_with("/") {
-                path_str = exec.cur_dir.to_str().unwrap().to_owned() + "/";
+        Some(path) if !path.starts_with('-') => match fs::read(path) {
+            Ok(source) => run_script(decode(source), new_context(argv[1..].to_vec())),
+            Err(e) => {
+                eprintln!("{}: {}: {}", shell, path, e);
+                process::exit(match e.kind() {
+                    std::io::ErrorKind::NotFound => 127,
+                    _ => 126,
+                });
             }
-            path_str += lines[1];
-            path_str = eval_path_str(&path_str);
-            let path = Path::new(path_str.as_str());
-            if path.is_dir() {
-                exec.cur_dir = path.into();
-            } else {
-                println!("{} is not a valid directory.", lines[1]);
+        },
+        Some(option) => {
+            eprintln!("{}: {}: invalid option\n{}", shell, option, USAGE);
+            2
+        }
+        None if stdin().is_terminal() => run_prompt(new_context(vec![shell])),
+        None => {
+            let mut source = Vec::new();
+            if let Err(e) = stdin().read_to_end(&mut source) {
+                eprintln!("{}: {}", shell, e);
+                process::exit(1);
             }
-            return true;
+            run_script(decode(source), new_context(vec![shell]))
         }
-        _ => {}
     }
-    return false;
 }
-fn run_prompt() {
-    let current_dir = current_dir().unwrap_or_else(|e| panic!("failed to get current dir: {}", e));
-    let mut current_dir_str = current_dir.to_str().unwrap().to_owned();
-    let mut exec = ExecContext::new(current_dir_str.clone());
-    let mut line = String::new();
-    loop {
-        print!("λ {} ", current_dir_str);
-        stdout().flush().expect("Failed to flush stdout");
-        stdin().read_line(&mut line).unwrap();
 
-        if check_special(line.clone(), &mut exec) {
-            current_dir_str = exec.cur_dir.to_str().unwrap().to_owned();
-            line.clear();
-            continue;
+/// Decodes source code read from a file or stdin, bytes that are not valid
+/// UTF-8 are replaced rather than rejecting the whole program.
+fn decode(source: Vec<u8>) -> String {
+    String::from_utf8(source).unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned())
+}
+
+fn run(source: &str, exec: &mut ExecContext) -> Result<i32, ShellError> {
+    let mut lexer = Scanner::new(source.to_string());
+    lexer.scan_tokens()?;
+    execute(lexer, exec)
+}
+
+fn execute(lexer: Scanner, exec: &mut ExecContext) -> Result<i32, ShellError> {
+    let ast = parse_program(lexer)?;
+    exec.interpret_program(&ast, &Io::default())
+}
+
+/// Runs a whole program at once and returns its exit status. A `#!` line at
+/// the top is skipped as a comment.
+fn run_script(source: String, mut exec: ExecContext) -> i32 {
+    let status = match run(&source, &mut exec) {
+        Ok(status) => match exec.flow {
+            Some(Flow::Exit(status)) => status,
+            _ => status,
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            e.status()
         }
-        let run_str = line.clone();
-        let exec_clone = exec.clone();
-        let out = thread::spawn(move || {
-            run(&run_str, exec_clone);
-        })
-        .join();
-        match out {
-            _ => {}
+    };
+    exec.exit_trap(status, &Io::default())
+}
+
+/// Reads and runs one line at a time until the input ends or `exit` is run.
+fn run_prompt(mut exec: ExecContext) -> i32 {
+    jobs::enable_job_control();
+    exec.options.insert(String::from("emacs"));
+    let mut editor = Editor::new();
+    let status = loop {
+        if let Err(e) = exec.run_signal_traps(&Io::default()) {
+            eprintln!("{}", e);
         }
-        line.clear();
-    }
+        exec.notify_jobs();
+        let prompt = format!("λ {} ", exec.cur_dir.display());
+        let Ok(Some(mut line)) = editor.read_line(&prompt, exec.option("vi")) else {
+            // End of input, the shell exits with the status of the last command.
+            break exec.last_status;
+        };
+        editor.add_history(&line);
+        let result = loop {
+            let mut lexer = Scanner::new(line.clone());
+            match lexer.scan_tokens() {
+                // The body of a heredoc goes on over the next lines.
+                Err(e) if lexer.in_heredoc() => match editor.read_line("> ", exec.option("vi")) {
+                    Ok(Some(more)) => line.push_str(&more),
+                    _ => break Err(e),
+                },
+                Err(e) => break Err(e),
+                Ok(()) => break execute(lexer, &mut exec),
+            }
+        };
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            exec.last_status = e.status();
+        }
+        if let Some(Flow::Exit(status)) = exec.flow {
+            break status;
+        }
+    };
+    exec.exit_trap(status, &Io::default())
 }
@@ -1,239 +1,821 @@
-use crate::ast::{Expr, Kind, Symbol, AST};
-use crate::error::error;
+use crate::ast::{CaseArm, CaseEnd, Command, CommandKind, Cond, Expr, Redirect, Symbol, AST};
+use crate::error::{ShellError, Span};
+use crate::expand::is_name;
 use crate::scanner::Scanner;
+use crate::test;
 use crate::token::{Token, TokenType, WTSType};
+use std::sync::Arc;
 
-fn parse_expression(toks: &mut Vec<Token>, in_paren: bool) -> Option<AST> {
-    let mut ast: AST = AST { exprs: vec![] };
-    let mut iscomment = false;
-    ast.exprs.push(Expr {
-        kind: Kind::Expr,
-        flags: Vec::new(),
-        value: Some(String::new()),
-        args: Vec::new(),
-        symbol: Symbol::None,
-        left: None,
-        right: None,
-    });
-    let mut counter = 0;
-    while toks.len() > 0 {
-        let tok = toks.remove(0);
-        if iscomment {
-            continue;
-        }
-        match tok.t_type {
-            TokenType::LeftParen => {
-                if ast.exprs[counter].left.is_none() {
-                    ast.exprs[counter].left = parse_expression(toks, true);
-                } else if !(ast.exprs[counter].right.is_none()
-                    && ast.exprs[counter].symbol == Symbol::None)
-                {
-                    ast.exprs[counter].right = parse_expression(toks, true);
-                } else {
-                    error(
-                        tok.line,
-                        String::from("Parse error on parsing expression ("),
-                    );
-                }
+struct Parser {
+    toks: Vec<Token>,
+    current: usize,
+    source: Arc<str>,
+}
+
+/// Maps a token onto the binary operator it represents, if any.
+fn binary_symbol(t_type: TokenType) -> Option<Symbol> {
+    match t_type {
+        TokenType::Pipe => Some(Symbol::Pipe),
+        TokenType::AndIf => Some(Symbol::AndIf),
+        TokenType::OrIf => Some(Symbol::OrIf),
+        _ => None,
+    }
+}
+
+/// Splits a `NAME=value` word into its name and value.
+fn assignment(word: &str) -> Option<(String, String)> {
+    let (name, value) = word.split_once('=')?;
+    is_name(name).then(|| (String::from(name), String::from(value)))
+}
+
+/// Whether `name` can name a function, which allows a few more characters than
+/// variable names, as in `build-all`.
+fn is_function_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.' | ':'))
+}
+
+/// Maps a token onto the redirection it represents, if any.
+fn redirect_symbol(t_type: TokenType) -> Option<Symbol> {
+    match t_type {
+        TokenType::RedirLeft => Some(Symbol::RedirLeft),
+        TokenType::RedirRight => Some(Symbol::RedirRight),
+        TokenType::ClobberRedirRight => Some(Symbol::ClobberRedirRight),
+        TokenType::ReadWriteRedir => Some(Symbol::ReadWriteRedir),
+        TokenType::DoubleRedirLeft | TokenType::DoubleRedirLeftDash => {
+            Some(Symbol::DoubleRedirLeft)
+        }
+        TokenType::HereString => Some(Symbol::HereString),
+        TokenType::DoubleRedirRight => Some(Symbol::DoubleRedirRight),
+        TokenType::DupRedirLeft => Some(Symbol::DupRedirLeft),
+        TokenType::DupRedirRight => Some(Symbol::DupRedirRight),
+        TokenType::AndRedirRight => Some(Symbol::AndRedirRight),
+        TokenType::AndDoubleRedirRight => Some(Symbol::AndDoubleRedirRight),
+        _ => None,
+    }
+}
+
+impl Parser {
+    fn new(toks: Vec<Token>, source: Arc<str>) -> Self {
+        Self {
+            toks,
+            current: 0,
+            source,
+        }
+    }
+    fn peek(&self) -> &Token {
+        &self.toks[self.current]
+    }
+    fn advance(&mut self) -> &Token {
+        if self.peek().t_type != TokenType::EOF {
+            self.current += 1;
+        }
+        &self.toks[self.current - 1]
+    }
+    fn check(&self, t_type: TokenType) -> bool {
+        self.peek().t_type == t_type
+    }
+    /// The span of the last token consumed.
+    fn previous(&self) -> Span {
+        self.toks[self.current.saturating_sub(1)].span
+    }
+    fn error(&self, span: Span, message: impl Into<String>) -> ShellError {
+        ShellError::parse(&self.source, span, message)
+    }
+    /// Whether the list being parsed ends here, at the end of the source or at
+    /// one of the tokens that close it.
+    fn at_end(&self, terminators: &[TokenType]) -> bool {
+        self.check(TokenType::EOF) || terminators.contains(&self.peek().t_type)
+    }
+    /// Whether the next token can be used as a word, which includes reserved
+    /// words outside of command position.
+    fn at_word(&self) -> bool {
+        self.check(TokenType::Word) || self.peek().t_type.is_keyword()
+    }
+
+    /// word → WORD | flag
+    ///
+    /// Consumes the next word, if any. A flag takes in the dashes and the word
+    /// written right after its dashes, so `---x` stays one word while
+    /// `expr 4 - 1` keeps `-` as a word of its own.
+    fn parse_word(&mut self) -> Option<String> {
+        if self.at_word() {
+            return Some(self.advance().lexeme.clone());
+        }
+        if !self.is_flag() {
+            return None;
+        }
+        let dash = self.advance();
+        let (mut end, mut flag) = (dash.span.end, dash.lexeme.clone());
+        while self.is_flag() && self.peek().span.start == end {
+            let dash = self.advance();
+            end = dash.span.end;
+            flag += &dash.lexeme;
+        }
+        if self.at_word() && self.peek().span.start == end {
+            flag += &self.advance().lexeme;
+        }
+        Some(flag)
+    }
+    /// Whether the next token is a run of dashes.
+    fn is_flag(&self) -> bool {
+        self.check(TokenType::ShortFlag) || self.check(TokenType::LongFlag)
+    }
+
+    /// Whether the next token ends an expression, i.e. is `;` or a newline.
+    fn separator(&self) -> bool {
+        self.check(TokenType::Semicolon) || self.check(TokenType::Newline)
+    }
+    /// Skips the newlines allowed after an operator, as in `a &&` followed by
+    /// `b` on the next line.
+    fn skip_newlines(&mut self) {
+        while self.check(TokenType::Newline) {
+            self.advance();
+        }
+    }
+
+    /// list → expression ( ( ";" | NEWLINE ) expression )* ( ";" | NEWLINE )?
+    ///
+    /// The list runs until the end of the source or one of `terminators`.
+    fn parse_list(&mut self, terminators: &[TokenType]) -> Result<AST, ShellError> {
+        let mut ast = AST {
+            exprs: Vec::new(),
+            source: self.source.clone(),
+        };
+        loop {
+            while self.separator() {
+                self.advance();
             }
-            TokenType::RightParen => {
-                if in_paren {
-                    return Some(ast);
+            if self.check(TokenType::Pound) {
+                // Everything after a comment marker up to the end of the line is
+                // ignored.
+                while !self.check(TokenType::EOF) && !self.check(TokenType::Newline) {
+                    self.advance();
                 }
-                error(tok.line, String::from("Parser error on parens"));
+                continue;
             }
-            TokenType::ShortFlag => {
-                let flag = toks.remove(0);
-                if flag.t_type != TokenType::Word {
-                    error(flag.line, String::from("Parser error: Invalid flag"));
-                }
-                if ast.exprs[counter].right.as_mut().is_none()
-                    && ast.exprs[counter].left.as_mut().unwrap().exprs[0].symbol == Symbol::Cmd
-                {
-                    ast.exprs[counter].left.as_mut().unwrap().exprs[0]
-                        .flags
-                        .push(String::from("-") + &flag.lexeme);
-                } else if ast.exprs[counter].right.as_mut().unwrap().exprs[0].symbol == Symbol::Cmd
-                {
-                    ast.exprs[counter].right.as_mut().unwrap().exprs[0]
-                        .flags
-                        .push(String::from("-") + &flag.lexeme);
-                } else {
-                    error(tok.line, String::from("Error when parsing flag"));
-                }
+            if self.at_end(terminators) {
+                break;
+            }
+            let start = self.peek().span.start;
+            let expr = self.parse_binary(0)?;
+            // `&` separates commands like `;` but does not wait for the one before.
+            if self.check(TokenType::Ampersand) {
+                let command = String::from(&self.source[start..self.previous().end]);
+                self.advance();
+                ast.exprs.push(Expr::Background {
+                    body: Arc::new(AST {
+                        exprs: vec![expr],
+                        source: self.source.clone(),
+                    }),
+                    command,
+                });
+                continue;
             }
-            TokenType::LongFlag => {
-                let flag = toks.remove(0);
-                if flag.t_type != TokenType::Word {
-                    error(flag.line, String::from("Parser error: Invalid flag"));
+            ast.exprs.push(expr);
+            if !self.separator() && !self.check(TokenType::Pound) && !self.at_end(terminators) {
+                let tok = self.peek();
+                let error = self.error(tok.span, format!("unexpected \"{}\"", tok.lexeme));
+                return Err(match tok.t_type {
+                    TokenType::RightParen => error.with_hint(
+                        "there is no \"(\" for it to close, did you mean to wrap this in parentheses?",
+                    ),
+                    TokenType::DoubleSemicolon
+                    | TokenType::SemicolonAnd
+                    | TokenType::DoubleSemicolonAnd => error.with_hint(format!(
+                        "\"{}\" can only end the commands of a case pattern",
+                        tok.lexeme
+                    )),
+                    _ => error.with_hint("separate commands with \";\" or a newline"),
+                });
+            }
+        }
+        Ok(ast)
+    }
+
+    /// Precedence climbing over the binary operators, `|` binds tighter than
+    /// `&&` and `||` which share a level and associate to the left.
+    fn parse_binary(&mut self, min_prec: u8) -> Result<Expr, ShellError> {
+        let mut left = Expr::Pipeline(vec![self.parse_command()?]);
+        while let Some(symbol) = binary_symbol(self.peek().t_type) {
+            if symbol.precedence() < min_prec {
+                break;
+            }
+            let op = self.advance();
+            let (op_span, op) = (op.span, op.lexeme.clone());
+            self.skip_newlines();
+            if self.separator() || self.at_end(&[]) {
+                return Err(self
+                    .error(op_span, format!("expected a command after \"{}\"", op))
+                    .with_hint(format!("\"{}\" needs a command on both sides", op)));
+            }
+            let right = self.parse_binary(symbol.precedence() + 1)?;
+            left = match (symbol, left, right) {
+                (Symbol::Pipe, Expr::Pipeline(mut stages), Expr::Pipeline(rest)) => {
+                    stages.extend(rest);
+                    Expr::Pipeline(stages)
                 }
-                if ast.exprs[counter].right.as_mut().is_none()
-                    && ast.exprs[counter].left.as_mut().unwrap().exprs[0].symbol == Symbol::Cmd
-                {
-                    ast.exprs[counter].left.as_mut().unwrap().exprs[0]
-                        .flags
-                        .push(String::from("--") + &flag.lexeme);
-                } else if ast.exprs[counter].right.as_mut().unwrap().exprs[0].symbol == Symbol::Cmd
-                {
-                    ast.exprs[counter].right.as_mut().unwrap().exprs[0]
-                        .flags
-                        .push(String::from("--") + &flag.lexeme);
-                } else {
-                    error(tok.line, String::from("Error when parsing flag"));
+                (Symbol::AndIf | Symbol::OrIf, left, right) => Expr::Binary {
+                    symbol,
+                    left: Box::new(left),
+                    right: Box::new(right),
+                },
+                (_, _, _) => {
+                    return Err(self.error(
+                        op_span,
+                        format!("Binary expression {:?} encountered parse error.", symbol),
+                    ));
                 }
+            };
+        }
+        Ok(left)
+    }
+
+    /// Parses the list of a compound command up to one of `terminators`, which
+    /// has to hold at least one command.
+    fn parse_body(&mut self, terminators: &[TokenType]) -> Result<AST, ShellError> {
+        let ast = self.parse_list(terminators)?;
+        if ast.exprs.is_empty() && !self.check(TokenType::EOF) {
+            let tok = self.peek();
+            return Err(self
+                .error(
+                    tok.span,
+                    format!("expected a command before \"{}\"", tok.lexeme),
+                )
+                .with_hint(format!(
+                    "put at least one command before \"{}\", \":\" does nothing",
+                    tok.lexeme
+                )));
+        }
+        Ok(ast)
+    }
+
+    /// Consumes the `closer` token that ends the compound command started by
+    /// the `opener` keyword at `start`.
+    fn expect(
+        &mut self,
+        closer: TokenType,
+        start: Span,
+        opener: &str,
+        keyword: &str,
+    ) -> Result<(), ShellError> {
+        if self.check(closer) {
+            self.advance();
+            return Ok(());
+        }
+        let tok = self.peek();
+        Err(match tok.t_type {
+            TokenType::EOF => self
+                .error(start, format!("unclosed \"{}\"", opener))
+                .with_hint(format!("add \"{}\" to go with it", keyword)),
+            _ => self
+                .error(
+                    tok.span,
+                    format!("expected \"{}\" before \"{}\"", keyword, tok.lexeme),
+                )
+                .with_hint(format!("\"{}\" needs \"{}\" here", opener, keyword)),
+        })
+    }
+
+    /// Whether the next tokens are `name ( )`, starting a function definition.
+    fn at_function(&self) -> bool {
+        let ahead = |n: usize| self.toks.get(self.current + n).map(|tok| tok.t_type);
+        self.check(TokenType::Word)
+            && ahead(1) == Some(TokenType::LeftParen)
+            && ahead(2) == Some(TokenType::RightParen)
+    }
+
+    /// command → ( "(" list ")" | "{" list "}" | "((" ARITHMETIC "))" | cond | if | loop | for | case ) redirect*
+    ///         | function
+    ///         | assignment* word+ redirect*
+    fn parse_command(&mut self) -> Result<Command, ShellError> {
+        let mut redirects = Vec::new();
+        let start = self.peek().span;
+        if self.check(TokenType::Function) || self.at_function() {
+            let kind = self.parse_function()?;
+            return Ok(Command {
+                kind,
+                redirects,
+                span: start.to(self.previous()),
+            });
+        }
+        let kind = match self.peek().t_type {
+            TokenType::LeftParen => Some(self.parse_subshell()?),
+            TokenType::LeftBrace => Some(self.parse_group()?),
+            TokenType::DoubleLeftBracket => Some(self.parse_cond()?),
+            TokenType::Arithmetic => {
+                let lexeme = &self.advance().lexeme;
+                Some(CommandKind::Arithmetic(String::from(
+                    &lexeme[2..lexeme.len() - 2],
+                )))
             }
-            TokenType::Semicolon => {
-                ast.exprs.push(Expr {
-                    kind: Kind::Expr,
-                    value: Some(String::new()),
-                    flags: Vec::new(),
-                    args: Vec::new(),
-                    symbol: Symbol::None,
-                    left: None,
-                    right: None,
-                });
-                counter += 1;
-            }
-            TokenType::Pipe => {
-                if ast.exprs[counter].left.is_none() || !ast.exprs[counter].right.is_none() {
-                    error(
-                        tok.line,
-                        String::from("Binary expression \"|\" encountered parse error."),
-                    );
+            TokenType::If => Some(self.parse_if()?),
+            TokenType::While | TokenType::Until => Some(self.parse_loop()?),
+            TokenType::For => Some(self.parse_for()?),
+            TokenType::Case => Some(self.parse_case()?),
+            _ => None,
+        };
+        if let Some(kind) = kind {
+            while self.parse_redirect(&mut redirects)? {}
+            return Ok(Command {
+                kind,
+                redirects,
+                span: start.to(self.previous()),
+            });
+        }
+        let mut assigns = Vec::new();
+        let mut words = Vec::new();
+        loop {
+            if self.parse_redirect(&mut redirects)? {
+                continue;
+            }
+            match self.peek().t_type {
+                TokenType::Word | TokenType::In => {
+                    let word = self.advance().lexeme.clone();
+                    // Assignments are only recognized before the command name.
+                    match assignment(&word) {
+                        Some(assign) if words.is_empty() => assigns.push(assign),
+                        _ => words.push(word),
+                    }
                 }
-                ast.exprs[counter].kind = Kind::Expr;
-                ast.exprs[counter].symbol = Symbol::Pipe;
-                ast.exprs[counter].value = Some(tok.lexeme);
-            }
-            TokenType::RedirLeft => {
-                if ast.exprs[counter].left.is_none() || !ast.exprs[counter].right.is_none() {
-                    error(
-                        tok.line,
-                        String::from("Binary expression \"<\" encountered parse error."),
-                    );
+                // Reserved words are only special in place of the command name.
+                t_type if t_type.is_keyword() && !words.is_empty() => {
+                    words.push(self.advance().lexeme.clone());
                 }
-                ast.exprs[counter].kind = Kind::Expr;
-                ast.exprs[counter].symbol = Symbol::RedirLeft;
-                ast.exprs[counter].value = Some(tok.lexeme);
-            }
-            TokenType::DoubleRedirLeft => {
-                if ast.exprs[counter].left.is_none() || !ast.exprs[counter].right.is_none() {
-                    error(
-                        tok.line,
-                        String::from("Binary expression \"<<\" encountered parse error."),
-                    );
+                TokenType::ShortFlag | TokenType::LongFlag => words.extend(self.parse_word()),
+                _ => break,
+            }
+        }
+        if words.is_empty() && assigns.is_empty() && redirects.is_empty() {
+            let tok = self.peek();
+            return Err(match tok.t_type {
+                TokenType::EOF => self.error(tok.span, "expected a command"),
+                TokenType::RightParen => self
+                    .error(tok.span, "expected a command before \")\"")
+                    .with_hint("a subshell needs at least one command"),
+                t_type if t_type.is_keyword() => self
+                    .error(tok.span, format!("unexpected \"{}\"", tok.lexeme))
+                    .with_hint(format!(
+                        "\"{}\" can only be used inside an if, case, while, until, for, {{ }} or [[ ]] command",
+                        tok.lexeme
+                    )),
+                _ => self
+                    .error(
+                        tok.span,
+                        format!("expected a command before \"{}\"", tok.lexeme),
+                    )
+                    .with_hint(format!("\"{}\" needs a command on both sides", tok.lexeme)),
+            });
+        }
+        Ok(Command {
+            kind: CommandKind::Simple { assigns, words },
+            redirects,
+            span: start.to(self.previous()),
+        })
+    }
+
+    /// subshell → "(" list ")"
+    fn parse_subshell(&mut self) -> Result<CommandKind, ShellError> {
+        let start = self.advance().span;
+        let ast = self.parse_list(&[TokenType::RightParen])?;
+        if ast.exprs.is_empty() && self.check(TokenType::RightParen) {
+            return Err(self
+                .error(self.peek().span, "expected a command before \")\"")
+                .with_hint("a subshell needs at least one command"));
+        }
+        if !self.check(TokenType::RightParen) {
+            return Err(self
+                .error(start, "unclosed \"(\"")
+                .with_hint("add a \")\" after the last command of the subshell"));
+        }
+        self.advance();
+        Ok(CommandKind::Subshell(ast))
+    }
+
+    /// group → "{" list "}"
+    fn parse_group(&mut self) -> Result<CommandKind, ShellError> {
+        let start = self.advance().span;
+        let ast = self.parse_body(&[TokenType::RightBrace])?;
+        self.expect(TokenType::RightBrace, start, "{", "}")?;
+        Ok(CommandKind::Group(ast))
+    }
+
+    /// cond → "[[" cond_or "]]"
+    fn parse_cond(&mut self) -> Result<CommandKind, ShellError> {
+        let start = self.advance().span;
+        let cond = self.parse_cond_or()?;
+        self.expect(TokenType::DoubleRightBracket, start, "[[", "]]")?;
+        Ok(CommandKind::Cond(cond))
+    }
+
+    /// Whether the next token ends an operand inside `[[ ]]`.
+    fn at_cond_end(&self) -> bool {
+        matches!(
+            self.peek().t_type,
+            TokenType::DoubleRightBracket
+                | TokenType::AndIf
+                | TokenType::OrIf
+                | TokenType::RightParen
+                | TokenType::Newline
+                | TokenType::EOF
+        )
+    }
+
+    /// Consumes the next operand inside `[[ ]]`, or fails pointing at what is
+    /// there instead.
+    fn cond_word(&mut self, what: &str) -> Result<String, ShellError> {
+        let word = match self.at_cond_end() {
+            true => None,
+            false => self.parse_word(),
+        };
+        word.ok_or_else(|| {
+            let tok = self.peek();
+            self.error(
+                tok.span,
+                format!("expected {} before \"{}\"", what, tok.lexeme),
+            )
+            .with_hint("write a test such as \"[[ -f file ]]\" or \"[[ $a == b* ]]\"")
+        })
+    }
+
+    /// cond_or → cond_and ( "||" cond_and )*
+    fn parse_cond_or(&mut self) -> Result<Cond, ShellError> {
+        let mut left = self.parse_cond_and()?;
+        while self.check(TokenType::OrIf) {
+            self.advance();
+            let right = self.parse_cond_and()?;
+            left = Cond::Or(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    /// cond_and → cond_not ( "&&" cond_not )*
+    fn parse_cond_and(&mut self) -> Result<Cond, ShellError> {
+        let mut left = self.parse_cond_not()?;
+        while self.check(TokenType::AndIf) {
+            self.advance();
+            let right = self.parse_cond_not()?;
+            left = Cond::And(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    /// cond_not → "!" cond_not | "(" cond_or ")" | UNARY_OP word | word ( BINARY_OP word )?
+    fn parse_cond_not(&mut self) -> Result<Cond, ShellError> {
+        self.skip_newlines();
+        if self.check(TokenType::LeftParen) {
+            let open = self.advance().span;
+            let cond = self.parse_cond_or()?;
+            self.skip_newlines();
+            self.expect(TokenType::RightParen, open, "(", ")")?;
+            return Ok(cond);
+        }
+        let word = self.cond_word("an expression")?;
+        if word == "!" && !self.at_cond_end() {
+            return Ok(Cond::Not(Box::new(self.parse_cond_not()?)));
+        }
+        if test::UNARY.contains(&word.as_str()) && !self.at_cond_end() {
+            let operand = self.cond_word(&format!("an operand for \"{}\"", word))?;
+            return Ok(Cond::Unary {
+                op: word,
+                word: operand,
+            });
+        }
+        // Backtracks when the next word turns out not to be an operator.
+        let before = self.current;
+        let op = match self.peek().t_type {
+            TokenType::RedirLeft | TokenType::RedirRight => Some(self.advance().lexeme.clone()),
+            _ if self.at_cond_end() => None,
+            _ => self
+                .parse_word()
+                .filter(|op| test::BINARY.contains(&op.as_str()) || op == "=~"),
+        };
+        let Some(op) = op else {
+            self.current = before;
+            return Ok(Cond::Word(word));
+        };
+        let right = match op.as_str() {
+            // A regex runs up to the first space, whatever characters it holds,
+            // so that `^(a|b)$` needs no quotes.
+            "=~" if !self.at_cond_end() => {
+                let Span { start, mut end, .. } = self.advance().span;
+                while !self.check(TokenType::EOF)
+                    && !self.check(TokenType::DoubleRightBracket)
+                    && self.peek().span.start == end
+                {
+                    end = self.advance().span.end;
                 }
-                ast.exprs[counter].kind = Kind::Expr;
-                ast.exprs[counter].symbol = Symbol::DoubleRedirLeft;
-                ast.exprs[counter].value = Some(tok.lexeme);
-            }
-            TokenType::RedirRight => {
-                if ast.exprs[counter].left.is_none() || !ast.exprs[counter].right.is_none() {
-                    error(
-                        tok.line,
-                        String::from("Binary expression \">\" encountered parse error."),
-                    );
+                String::from(&self.source[start..end])
+            }
+            _ => self.cond_word(&format!("an operand for \"{}\"", op))?,
+        };
+        Ok(Cond::Binary {
+            op,
+            left: word,
+            right,
+        })
+    }
+
+    /// function → NAME "(" ")" NEWLINE* compound_command
+    ///          | "function" NAME ( "(" ")" )? NEWLINE* compound_command
+    fn parse_function(&mut self) -> Result<CommandKind, ShellError> {
+        if self.check(TokenType::Function) {
+            self.advance();
+        }
+        let tok = self.peek();
+        if !self.at_word() || !is_function_name(&tok.lexeme) {
+            return Err(self
+                .error(
+                    tok.span,
+                    format!("\"{}\" is not a valid function name", tok.lexeme),
+                )
+                .with_hint("name the function, as in \"greet() { echo hello; }\""));
+        }
+        let name = self.advance().lexeme.clone();
+        // The parentheses are optional after the `function` keyword.
+        if self.check(TokenType::LeftParen) {
+            let open = self.advance().span;
+            self.expect(TokenType::RightParen, open, "(", ")")?;
+        }
+        self.skip_newlines();
+        if !matches!(
+            self.peek().t_type,
+            TokenType::LeftBrace
+                | TokenType::LeftParen
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Until
+                | TokenType::For
+                | TokenType::Case
+        ) {
+            let tok = self.peek();
+            return Err(self
+                .error(
+                    tok.span,
+                    format!(
+                        "expected the body of \"{}\" before \"{}\"",
+                        name, tok.lexeme
+                    ),
+                )
+                .with_hint("wrap the body of the function in braces, as in \"{ echo hello; }\""));
+        }
+        let body = self.parse_command()?;
+        Ok(CommandKind::Function {
+            name,
+            body: Arc::new(AST {
+                exprs: vec![Expr::Pipeline(vec![body])],
+                source: self.source.clone(),
+            }),
+        })
+    }
+
+    /// if → "if" list "then" list ( "elif" list "then" list )* ( "else" list )? "fi"
+    fn parse_if(&mut self) -> Result<CommandKind, ShellError> {
+        let start = self.advance().span;
+        let mut branches = Vec::new();
+        let mut otherwise = None;
+        loop {
+            let cond = self.parse_body(&[TokenType::Then])?;
+            self.expect(TokenType::Then, start, "if", "then")?;
+            let body = self.parse_body(&[TokenType::Elif, TokenType::Else, TokenType::Fi])?;
+            branches.push((cond, body));
+            match self.peek().t_type {
+                TokenType::Elif => {
+                    self.advance();
                 }
-                ast.exprs[counter].kind = Kind::Expr;
-                ast.exprs[counter].symbol = Symbol::RedirRight;
-                ast.exprs[counter].value = Some(tok.lexeme);
-            }
-            TokenType::DoubleRedirRight => {
-                if ast.exprs[counter].left.is_none() || !ast.exprs[counter].right.is_none() {
-                    error(
-                        tok.line,
-                        String::from("Binary expression \">>\" encountered parse error."),
-                    );
+                TokenType::Else => {
+                    self.advance();
+                    otherwise = Some(self.parse_body(&[TokenType::Fi])?);
+                    break;
                 }
-                ast.exprs[counter].kind = Kind::Expr;
-                ast.exprs[counter].symbol = Symbol::DoubleRedirRight;
-                ast.exprs[counter].value = Some(tok.lexeme);
-            }
-            TokenType::Pound => {
-                iscomment = true;
-            }
-            TokenType::Word | TokenType::String => {
-                if ast.exprs[counter].left.is_none() {
-                    ast.exprs[counter].left = Some(AST { exprs: Vec::new() });
-                    let val = match tok.literal {
-                        WTSType::String(s) => s,
-                        _ => tok.lexeme,
-                    };
-                    ast.exprs[counter].left.as_mut().unwrap().exprs.push(Expr {
-                        kind: Kind::Value,
-                        flags: Vec::new(),
-                        value: Some(val),
-                        args: Vec::new(),
-                        symbol: Symbol::Cmd,
-                        left: None,
-                        right: None,
-                    });
-                } else if ast.exprs[counter].symbol == Symbol::None
-                    && ast.exprs[counter].left.as_mut().unwrap().exprs[0].symbol == Symbol::Cmd
-                {
-                    let val = match tok.literal {
-                        WTSType::String(s) => s,
-                        _ => tok.lexeme,
-                    };
-                    ast.exprs[counter].left.as_mut().unwrap().exprs[0]
-                        .args
-                        .push(val);
-                } else if ast.exprs[counter].right.is_none() {
-                    ast.exprs[counter].right = Some(AST { exprs: Vec::new() });
-                    let mut sym = Symbol::None;
-                    match ast.exprs[counter].symbol {
-                        Symbol::RedirLeft => {
-                            sym = Symbol::File;
-                        }
-                        Symbol::DoubleRedirLeft => {
-                            sym = Symbol::String;
-                        }
-                        Symbol::RedirRight => {
-                            sym = Symbol::File;
-                        }
-                        Symbol::DoubleRedirRight => {
-                            sym = Symbol::File;
-                        }
-                        Symbol::Pipe => {
-                            sym = Symbol::Cmd;
-                        }
-                        _ => error(
-                            tok.line,
-                            String::from("Parse error, right hand command without "),
-                        ),
-                    }
-                    ast.exprs[counter].right.as_mut().unwrap().exprs.push(Expr {
-                        kind: Kind::Value,
-                        flags: Vec::new(),
-                        value: Some(String::from(tok.lexeme)),
-                        args: Vec::new(),
-                        symbol: sym,
-                        left: None,
-                        right: None,
-                    });
-                } else if ast.exprs[counter].right.as_mut().unwrap().exprs[0].symbol == Symbol::Cmd
-                {
-                    ast.exprs[counter].right.as_mut().unwrap().exprs[0]
-                        .args
-                        .push(tok.lexeme);
-                } else {
-                    error(
-                        tok.line,
-                        String::from("Parse error when handling expression"),
-                    );
+                _ => break,
+            }
+        }
+        self.expect(TokenType::Fi, start, "if", "fi")?;
+        Ok(CommandKind::If {
+            branches,
+            otherwise,
+        })
+    }
+
+    /// loop → ( "while" | "until" ) list "do" list "done"
+    fn parse_loop(&mut self) -> Result<CommandKind, ShellError> {
+        let keyword = self.advance();
+        let (start, until) = (keyword.span, keyword.t_type == TokenType::Until);
+        let opener = if until { "until" } else { "while" };
+        let cond = self.parse_body(&[TokenType::Do])?;
+        self.expect(TokenType::Do, start, opener, "do")?;
+        let body = self.parse_body(&[TokenType::Done])?;
+        self.expect(TokenType::Done, start, opener, "done")?;
+        Ok(CommandKind::Loop { until, cond, body })
+    }
+
+    /// for → "for" NAME ( NEWLINE* "in" WORD* )? ( ";" | NEWLINE )* "do" list "done"
+    fn parse_for(&mut self) -> Result<CommandKind, ShellError> {
+        let start = self.advance().span;
+        let tok = self.peek();
+        if !self.at_word() || !is_name(&tok.lexeme) {
+            return Err(self
+                .error(
+                    tok.span,
+                    format!("\"{}\" is not a valid variable name", tok.lexeme),
+                )
+                .with_hint("name the loop variable, as in \"for file in *\""));
+        }
+        let name = self.advance().lexeme.clone();
+        self.skip_newlines();
+        let mut words = None;
+        if self.check(TokenType::In) {
+            self.advance();
+            let mut list = Vec::new();
+            while let Some(word) = self.parse_word() {
+                list.push(word);
+            }
+            words = Some(list);
+        }
+        while self.separator() {
+            self.advance();
+        }
+        self.expect(TokenType::Do, start, "for", "do")?;
+        let body = self.parse_body(&[TokenType::Done])?;
+        self.expect(TokenType::Done, start, "for", "done")?;
+        Ok(CommandKind::For { name, words, body })
+    }
+
+    /// case → "case" word NEWLINE* "in" NEWLINE* case_arm* "esac"
+    ///
+    /// case_arm → "("? word ( "|" word )* ")" list ( ";;" | ";&" | ";;&" )? NEWLINE*
+    fn parse_case(&mut self) -> Result<CommandKind, ShellError> {
+        let start = self.advance().span;
+        let Some(word) = self.parse_word() else {
+            let tok = self.peek();
+            return Err(self
+                .error(
+                    tok.span,
+                    format!("expected a word after \"case\", found \"{}\"", tok.lexeme),
+                )
+                .with_hint("name what to match, as in \"case $1 in\""));
+        };
+        self.skip_newlines();
+        self.expect(TokenType::In, start, "case", "in")?;
+        let mut arms = Vec::new();
+        loop {
+            self.skip_newlines();
+            if self.check(TokenType::Esac) || self.check(TokenType::EOF) {
+                break;
+            }
+            if self.check(TokenType::LeftParen) {
+                self.advance();
+            }
+            let mut patterns = Vec::new();
+            loop {
+                let Some(pattern) = self.parse_word() else {
+                    let tok = self.peek();
+                    return Err(self
+                        .error(
+                            tok.span,
+                            format!("expected a pattern before \"{}\"", tok.lexeme),
+                        )
+                        .with_hint("write the pattern to match, as in \"*.txt)\""));
+                };
+                patterns.push(pattern);
+                if !self.check(TokenType::Pipe) {
+                    break;
                 }
+                self.advance();
+            }
+            self.expect(TokenType::RightParen, start, "case", ")")?;
+            let body = self.parse_list(&[
+                TokenType::DoubleSemicolon,
+                TokenType::SemicolonAnd,
+                TokenType::DoubleSemicolonAnd,
+                TokenType::Esac,
+            ])?;
+            let end = match self.peek().t_type {
+                TokenType::SemicolonAnd => CaseEnd::FallThrough,
+                TokenType::DoubleSemicolonAnd => CaseEnd::Continue,
+                _ => CaseEnd::Break,
+            };
+            // The last arm can leave out its operator and go straight to `esac`.
+            if !self.check(TokenType::Esac) && !self.check(TokenType::EOF) {
+                self.advance();
             }
-            _ => {}
+            arms.push(CaseArm {
+                patterns,
+                body,
+                end,
+            });
         }
+        self.expect(TokenType::Esac, start, "case", "esac")?;
+        Ok(CommandKind::Case { word, arms })
     }
-    if in_paren {
-        error(0, String::from("Expected closing \")\""));
+
+    /// redirect → IO_NUMBER? redirect_operator WORD
+    ///
+    /// Returns whether a redirection was consumed.
+    fn parse_redirect(&mut self, redirects: &mut Vec<Redirect>) -> Result<bool, ShellError> {
+        let start = self.current;
+        let mut fd = None;
+        if self.check(TokenType::IoNumber) {
+            if let WTSType::Number(n) = self.advance().literal {
+                fd = Some(n);
+            }
+        }
+        let Some(symbol) = redirect_symbol(self.peek().t_type) else {
+            self.current = start;
+            return Ok(false);
+        };
+        let span = self.toks[start].span;
+        let op = self.advance();
+        let (op_span, op) = (op.span, op.lexeme.clone());
+        let (symbol, target) = match (symbol, &self.peek().literal) {
+            // The scanner has already read the body of the heredoc into its
+            // delimiter, which stops it from being expanded when quoted.
+            (Symbol::DoubleRedirLeft, WTSType::String(body)) => {
+                let symbol = match self.peek().lexeme.contains(['\'', '"', '\\']) {
+                    true => Symbol::DoubleRedirLeftQuoted,
+                    false => symbol,
+                };
+                let body = body.clone();
+                self.advance();
+                (symbol, body)
+            }
+            // A flag is allowed so that `>&-` can close a descriptor.
+            _ => match self.parse_word() {
+                Some(word) => (symbol, word),
+                None if symbol == Symbol::DoubleRedirLeft => {
+                    return Err(self
+                        .error(op_span, format!("expected a delimiter after \"{}\"", op))
+                        .with_hint(format!(
+                            "name the line that ends the heredoc, as in \"{}EOF\"",
+                            op
+                        )))
+                }
+                None => {
+                    return Err(self
+                        .error(op_span, format!("expected a file after \"{}\"", op))
+                        .with_hint(format!(
+                            "name the file to redirect to, as in \"{} file\"",
+                            op
+                        )))
+                }
+            },
+        };
+        let fd = fd.unwrap_or(match symbol {
+            Symbol::RedirLeft
+            | Symbol::ReadWriteRedir
+            | Symbol::DoubleRedirLeft
+            | Symbol::DoubleRedirLeftQuoted
+            | Symbol::HereString
+            | Symbol::DupRedirLeft => 0,
+            _ => 1,
+        });
+        redirects.push(Redirect {
+            fd,
+            symbol,
+            target,
+            span: span.to(self.previous()),
+        });
+        Ok(true)
     }
-    return Some(ast);
 }
 
-pub fn parse_program(mut lexer: Scanner) -> Option<AST> {
+pub fn parse_program(lexer: Scanner) -> Result<AST, ShellError> {
     //println!("Entering parser\n{:#?}",lexer.tokens);
-    let ast = parse_expression(&mut lexer.tokens, false);
-    return ast;
+    let mut parser = Parser::new(lexer.tokens, Arc::from(lexer.source));
+    parser.parse_list(&[])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The words of the single simple command in `source`.
+    fn words(source: &str) -> Vec<String> {
+        let mut lexer = Scanner::new(String::from(source));
+        lexer.scan_tokens().unwrap();
+        let ast = parse_program(lexer).unwrap();
+        match ast.exprs.as_slice() {
+            [Expr::Pipeline(cmds)] => match &cmds[0].kind {
+                CommandKind::Simple { words, .. } => words.clone(),
+                kind => panic!("expected a simple command, got {:?}", kind),
+            },
+            exprs => panic!("expected one pipeline, got {:?}", exprs),
+        }
+    }
+
+    #[test]
+    fn flags_stay_whole_words() {
+        assert_eq!(words("cmd --foo -- -bar"), ["cmd", "--foo", "--", "-bar"]);
+        assert_eq!(words("echo ---x ----"), ["echo", "---x", "----"]);
+        assert_eq!(words("ls -la --color=auto"), ["ls", "-la", "--color=auto"]);
+    }
+
+    #[test]
+    fn lone_dashes_are_words() {
+        assert_eq!(words("expr 4 - 1"), ["expr", "4", "-", "1"]);
+        assert_eq!(words("cat - -- x"), ["cat", "-", "--", "x"]);
+    }
 }
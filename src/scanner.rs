@@ -1,29 +1,48 @@
-use crate::error::error;
+use crate::error::{ShellError, Span};
 use crate::token::{Token, TokenType, WTSType};
 use lazy_static::lazy_static;
 use std::collections::HashMap;
 
 #[derive(Debug)]
 pub struct Scanner {
-    source: String,
+    pub source: String,
     pub tokens: Vec<Token>,
     start: usize,
     current: usize,
     line: usize,
+    /// Heredocs whose body starts after the next newline, as the index of their
+    /// operator token and whether leading tabs are stripped.
+    heredocs: Vec<(usize, bool)>,
 }
 
 lazy_static! {
     static ref KEYWORDS: HashMap<&'static str, TokenType> = HashMap::from([
         //("WTScript",TokenType::SCRIPT),
         //("and", TokenType::AND),
-        //("else", TokenType::ELSE),
+        ("if", TokenType::If),
+        ("then", TokenType::Then),
+        ("elif", TokenType::Elif),
+        ("else", TokenType::Else),
+        ("fi", TokenType::Fi),
+        ("while", TokenType::While),
+        ("until", TokenType::Until),
+        ("for", TokenType::For),
+        ("in", TokenType::In),
+        ("do", TokenType::Do),
+        ("done", TokenType::Done),
+        ("case", TokenType::Case),
+        ("esac", TokenType::Esac),
+        ("function", TokenType::Function),
+        // Braces are reserved words like `if`, so `{` only opens a group where a
+        // command can start and has to be followed by a space.
+        ("{", TokenType::LeftBrace),
+        ("}", TokenType::RightBrace),
+        ("[[", TokenType::DoubleLeftBracket),
+        ("]]", TokenType::DoubleRightBracket),
         //("false", TokenType::FALSE),
-        //("for", TokenType::FOR),
-        //("if", TokenType::IF),
         //("or", TokenType::OR),
         //("true", TokenType::TRUE),
         //("var", TokenType::VAR),
-        //("while", TokenType::WHILE)
     ]);
 }
 
@@ -35,95 +54,244 @@ impl Scanner {
             start: 0,
             current: 0,
             line: 1,
+            heredocs: Vec::new(),
         }
     }
     fn advance(&mut self) -> char {
-        let ret: char = self.source.chars().nth(self.current).unwrap();
-        self.current += 1;
-        return ret;
+        let ret: char = self.source[self.current..].chars().next().unwrap();
+        self.current += ret.len_utf8();
+        ret
     }
     fn add_token(&mut self, t_type: TokenType) {
         let lexeme = &self.source.as_mut_str()[self.start..self.current];
-        let tok: Token = Token::new(t_type, WTSType::NONE, String::from(lexeme), self.line);
+        let tok: Token = Token::new(t_type, WTSType::NONE, String::from(lexeme), self.span());
         self.tokens.push(tok);
     }
-    fn match_next(&mut self, expected: char) -> bool {
-        if !(self.current < self.source.len()) {
-            return false;
+    /// The span of the token being scanned.
+    fn span(&self) -> Span {
+        Span {
+            start: self.start,
+            end: self.current,
+            line: self.line,
         }
-        if !(self.source.chars().nth(self.current).unwrap() == expected) {
+    }
+    fn match_next(&mut self, expected: char) -> bool {
+        if self.peek() != expected || self.current >= self.source.len() {
             return false;
         }
-        self.current += 1;
-        return true;
+        self.current += expected.len_utf8();
+        true
     }
     fn peek(&mut self) -> char {
-        if !(self.current < self.source.len()) {
+        if self.current >= self.source.len() {
             return '\0';
         }
-        return self.source.chars().nth(self.current).unwrap();
+        self.source[self.current..].chars().next().unwrap()
     }
-    fn string(&mut self) {
-        while self.peek() != '"' && self.current < self.source.len() {
-            if self.peek() == '\n' {
-                self.line += 1;
+    /// Skips over a quoted section of a word up to and including `quote`, the
+    /// opening quote has already been consumed.
+    fn string(&mut self, quote: char) -> Result<(), ShellError> {
+        let open = Span {
+            start: self.current - quote.len_utf8(),
+            end: self.current,
+            line: self.line,
+        };
+        while self.peek() != quote && self.current < self.source.len() {
+            match self.advance() {
+                '\n' => self.line += 1,
+                '\\' if quote != '\'' && self.current < self.source.len() => {
+                    self.advance();
+                }
+                c @ ('$' | '`') if quote == '"' => self.word_char(c)?,
+                _ => {}
             }
-            self.advance();
         }
         if self.current >= self.source.len() {
-            error(self.line, String::from("Unterminated String"));
-            return;
+            return Err(ShellError::lex(&self.source, open, "unterminated string")
+                .with_hint(format!("add a closing {} to end the string", quote)));
         }
         self.advance();
-        let lexeme = &self.source.as_mut_str()[self.start..self.current];
-        let literal = &lexeme[1..lexeme.len() - 1];
-        self.tokens.push(Token::new(
-            TokenType::String,
-            WTSType::String(String::from(literal)),
-            String::from(lexeme),
-            self.line,
-        ));
+        Ok(())
     }
-    /*
-        fn number(&mut self) {
-        while self.peek().is_digit(10) {
-            self.advance();
+    /// Skips over a `${...}` or `$(...)` expansion up to the matching `close`,
+    /// the `$` has already been consumed.
+    fn nested(&mut self, open: char, close: char) -> Result<(), ShellError> {
+        let dollar = Span {
+            start: self.current - 1,
+            end: self.current + 1,
+            line: self.line,
+        };
+        let mut depth = 0;
+        while self.current < self.source.len() {
+            match self.advance() {
+                '\\' if self.current < self.source.len() => {
+                    self.advance();
+                }
+                c if c == open => depth += 1,
+                c if c == close => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(());
+                    }
+                }
+                c @ ('"' | '\'' | '`') => self.word_char(c)?,
+                '\n' => self.line += 1,
+                _ => {}
+            }
         }
-        if self.peek() == '.' && self.peekNext().is_digit(10) {
-            self.advance();
-            while self.peek().is_digit(10) {
-                self.advance();
+        Err(
+            ShellError::lex(&self.source, dollar, format!("unterminated \"${}\"", open))
+                .with_hint(format!("add a closing \"{}\"", close)),
+        )
+    }
+    /// Scans a `(( ... ))` command, the first `(` has already been consumed.
+    /// Returns `false` and consumes nothing when the two parentheses do not close
+    /// together, as in `((cd src); make)` which starts with nested subshells.
+    fn arithmetic(&mut self) -> Result<bool, ShellError> {
+        let (current, line) = (self.current, self.line);
+        let mut depth = 0;
+        while self.current < self.source.len() {
+            match self.advance() {
+                '(' => depth += 1,
+                ')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                '\n' => self.line += 1,
+                c => self.word_char(c)?,
             }
         }
-        let lexeme = &self.source.as_mut_str()[self.start..self.current];
-        self.tokens.push(Token::new(
-            TokenType::IONUMBER,
-            WTSType::Number(lexeme.parse::<i32>().unwrap()),
-            String::from(lexeme),
-            self.line,
-        ));
+        if depth == 0 && self.match_next(')') {
+            self.add_token(TokenType::Arithmetic);
+            return Ok(true);
+        }
+        self.current = current;
+        self.line = line;
+        Ok(false)
+    }
+    /// Reads the bodies of the heredocs started on the line that just ended, one
+    /// after the other, and hands each one to the token of its delimiter.
+    fn heredoc_bodies(&mut self) -> Result<(), ShellError> {
+        while let Some(&(op, strip)) = self.heredocs.first() {
+            // Without a delimiter the parser reports the missing word.
+            let Some(word) = self
+                .tokens
+                .get(op + 1)
+                .filter(|t| t.t_type == TokenType::Word)
+            else {
+                self.heredocs.remove(0);
+                continue;
+            };
+            let delimiter: String = word
+                .lexeme
+                .chars()
+                .filter(|c| !matches!(c, '\'' | '"' | '\\'))
+                .collect();
+            let mut body = String::new();
+            loop {
+                if self.current >= self.source.len() {
+                    return Err(ShellError::lex(
+                        &self.source,
+                        self.tokens[op].span.to(self.tokens[op + 1].span),
+                        "unterminated heredoc",
+                    )
+                    .with_hint(format!("add a line with just \"{}\" to end it", delimiter)));
+                }
+                let end = self.source[self.current..]
+                    .find('\n')
+                    .map_or(self.source.len(), |n| self.current + n);
+                let line = &self.source[self.current..end];
+                let line = if strip {
+                    line.trim_start_matches('\t')
+                } else {
+                    line
+                };
+                let done = line == delimiter;
+                if !done {
+                    body += line;
+                    body.push('\n');
+                }
+                if end < self.source.len() {
+                    self.line += 1;
+                }
+                self.current = (end + 1).min(self.source.len());
+                if done {
+                    break;
+                }
+            }
+            self.tokens[op + 1].literal = WTSType::String(body);
+            self.heredocs.remove(0);
+        }
+        Ok(())
+    }
+    /// Whether the source ended inside the body of a heredoc, so more lines
+    /// have to be read before it can run.
+    pub fn in_heredoc(&self) -> bool {
+        !self.heredocs.is_empty()
     }
-    */
 
-    fn identifier(&mut self) {
-        let keys = vec!['(', ')', '-', ';', '#', '>', '<', '#', '|'];
-        while !self.peek().is_whitespace() && !keys.contains(&self.peek()) {
-            self.advance();
+    /// Consumes whatever has to stay attached to the word character `c`, such as
+    /// the rest of a quoted string or an escaped character.
+    fn word_char(&mut self, c: char) -> Result<(), ShellError> {
+        match c {
+            '\\' if self.current < self.source.len() => {
+                self.advance();
+            }
+            '"' | '\'' | '`' => self.string(c)?,
+            '$' => match self.peek() {
+                '{' => self.nested('{', '}')?,
+                '(' => self.nested('(', ')')?,
+                '#' => {
+                    self.advance();
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+    /// Scans a word, keeping its quotes and expansions intact for the expander.
+    fn identifier(&mut self) -> Result<(), ShellError> {
+        let keys = ['(', ')', ';', '#', '>', '<', '#', '|', '&'];
+        while self.current < self.source.len()
+            && !self.peek().is_whitespace()
+            && !keys.contains(&self.peek())
+        {
+            let c = self.advance();
+            self.word_char(c)?;
         }
+        let next = self.peek();
         let key = &self.source.as_mut_str()[self.start..self.current];
-        let type_of = KEYWORDS.get(key);
-        if type_of.is_none() {
-            self.add_token(TokenType::Word);
-        } else {
-            self.add_token(*type_of.unwrap());
+        if key.chars().all(|c| c.is_ascii_digit()) && (next == '<' || next == '>') {
+            if let Ok(fd) = key.parse::<i32>() {
+                self.tokens.push(Token::new(
+                    TokenType::IoNumber,
+                    WTSType::Number(fd),
+                    String::from(key),
+                    self.span(),
+                ));
+                return Ok(());
+            }
+        }
+        match KEYWORDS.get(key) {
+            Some(type_of) => self.add_token(*type_of),
+            None => self.add_token(TokenType::Word),
         }
+        Ok(())
     }
-    pub fn scan_tokens(&mut self) {
+    /// Splits the source into tokens, ending with an `EOF` token.
+    pub fn scan_tokens(&mut self) -> Result<(), ShellError> {
         while self.current < self.source.len() {
             self.start = self.current;
             let c = self.advance();
             match c {
-                '(' => self.add_token(TokenType::LeftParen),
+                '(' => {
+                    if !(self.peek() == '(' && self.arithmetic()?) {
+                        self.add_token(TokenType::LeftParen);
+                    }
+                }
                 ')' => self.add_token(TokenType::RightParen),
                 //'{' => self.add_token(TokenType::LEFT_BRACE),
                 //'}' => self.add_token(TokenType::RIGHT_BRACE),
@@ -136,7 +304,19 @@ impl Scanner {
                     }
                 }
                 //'+' => self.add_token(TokenType::PLUS),
-                ';' => self.add_token(TokenType::Semicolon),
+                ';' => {
+                    if self.match_next(';') {
+                        if self.match_next('&') {
+                            self.add_token(TokenType::DoubleSemicolonAnd);
+                        } else {
+                            self.add_token(TokenType::DoubleSemicolon);
+                        }
+                    } else if self.match_next('&') {
+                        self.add_token(TokenType::SemicolonAnd);
+                    } else {
+                        self.add_token(TokenType::Semicolon);
+                    }
+                }
                 //'*' => self.add_token(TokenType::STAR),
                 //'!' => {
                 //    if self.match_next('=') {
@@ -153,11 +333,41 @@ impl Scanner {
                 //    }
                 //}
                 '|' => {
-                    self.add_token(TokenType::Pipe);
+                    if self.match_next('|') {
+                        self.add_token(TokenType::OrIf);
+                    } else {
+                        self.add_token(TokenType::Pipe);
+                    }
+                }
+                '&' => {
+                    if self.match_next('&') {
+                        self.add_token(TokenType::AndIf);
+                    } else if self.match_next('>') {
+                        if self.match_next('>') {
+                            self.add_token(TokenType::AndDoubleRedirRight);
+                        } else {
+                            self.add_token(TokenType::AndRedirRight);
+                        }
+                    } else {
+                        self.add_token(TokenType::Ampersand);
+                    }
                 }
                 '<' => {
                     if self.match_next('<') {
-                        self.add_token(TokenType::DoubleRedirLeft);
+                        let strip = self.match_next('-');
+                        if !strip && self.match_next('<') {
+                            self.add_token(TokenType::HereString);
+                        } else {
+                            self.heredocs.push((self.tokens.len(), strip));
+                            self.add_token(match strip {
+                                true => TokenType::DoubleRedirLeftDash,
+                                false => TokenType::DoubleRedirLeft,
+                            });
+                        }
+                    } else if self.match_next('&') {
+                        self.add_token(TokenType::DupRedirLeft);
+                    } else if self.match_next('>') {
+                        self.add_token(TokenType::ReadWriteRedir);
                     } else {
                         self.add_token(TokenType::RedirLeft);
                     }
@@ -165,6 +375,10 @@ impl Scanner {
                 '>' => {
                     if self.match_next('>') {
                         self.add_token(TokenType::DoubleRedirRight);
+                    } else if self.match_next('&') {
+                        self.add_token(TokenType::DupRedirRight);
+                    } else if self.match_next('|') {
+                        self.add_token(TokenType::ClobberRedirRight);
                     } else {
                         self.add_token(TokenType::RedirRight);
                     }
@@ -178,21 +392,28 @@ impl Scanner {
                         self.add_token(TokenType::Pound);
                     }
                 }
-                '\n' => self.line += 1,
+                '\n' => {
+                    self.add_token(TokenType::Newline);
+                    self.line += 1;
+                    self.heredoc_bodies()?;
+                }
                 ' ' => (),
                 '\r' => (),
                 '\t' => (),
-                '"' => self.string(),
-                _ => {
-                    self.identifier();
+                c => {
+                    self.word_char(c)?;
+                    self.identifier()?;
                 }
             }
         }
+        self.heredoc_bodies()?;
+        self.start = self.current;
         self.tokens.push(Token::new(
             TokenType::EOF,
             WTSType::NONE,
             String::new(),
-            self.line,
+            self.span(),
         ));
+        Ok(())
     }
 }
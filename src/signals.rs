@@ -0,0 +1,458 @@
+use crate::ast::AST;
+use crate::builtins::quote;
+use crate::error::ShellError;
+use crate::interpreter::{ExecContext, Flow, Io};
+use crate::jobs;
+use crate::parser::parse_program;
+use crate::scanner::Scanner;
+use std::io::{self, Write};
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// The signals known by name to `trap` and `kill`, without their `SIG` prefix.
+const SIGNALS: [(&str, i32); 28] = [
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("ILL", libc::SIGILL),
+    ("TRAP", libc::SIGTRAP),
+    ("ABRT", libc::SIGABRT),
+    ("BUS", libc::SIGBUS),
+    ("FPE", libc::SIGFPE),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("SEGV", libc::SIGSEGV),
+    ("USR2", libc::SIGUSR2),
+    ("PIPE", libc::SIGPIPE),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+    ("CHLD", libc::SIGCHLD),
+    ("CONT", libc::SIGCONT),
+    ("STOP", libc::SIGSTOP),
+    ("TSTP", libc::SIGTSTP),
+    ("TTIN", libc::SIGTTIN),
+    ("TTOU", libc::SIGTTOU),
+    ("URG", libc::SIGURG),
+    ("XCPU", libc::SIGXCPU),
+    ("XFSZ", libc::SIGXFSZ),
+    ("VTALRM", libc::SIGVTALRM),
+    ("PROF", libc::SIGPROF),
+    ("WINCH", libc::SIGWINCH),
+    ("IO", libc::SIGIO),
+];
+
+/// The signals that arrived and still have to be handled, one bit per signal
+/// number.
+static PENDING: AtomicU64 = AtomicU64::new(0);
+
+/// Installed for every signal with a trap. Only marks the signal as pending, its
+/// commands are run by the shell once the command being run is done.
+extern "C" fn record(signal: libc::c_int) {
+    PENDING.fetch_or(1 << signal, Ordering::SeqCst);
+}
+
+/// Clears the pending mark of `signal`, returning whether it was set.
+fn take_pending(signal: i32) -> bool {
+    PENDING.fetch_and(!(1 << signal), Ordering::SeqCst) & (1 << signal) != 0
+}
+
+/// Blocks every signal on the calling thread, which leaves them to the shell's
+/// own threads. A signal that kills the command the shell waits for is then
+/// marked as pending before the shell sees the command exit. Threads started
+/// afterwards inherit the mask, so the shell thread undoes it with
+/// [`unblock_signals`].
+pub fn block_signals() {
+    set_mask(libc::SIG_BLOCK);
+}
+
+/// Delivers every signal to the calling thread again.
+pub fn unblock_signals() {
+    set_mask(libc::SIG_UNBLOCK);
+}
+
+/// Applies `how` to the signal mask of the calling thread with every signal.
+fn set_mask(how: libc::c_int) {
+    // SAFETY: `set` is initialized by `sigfillset` before it is used.
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigfillset(&mut set);
+        libc::pthread_sigmask(how, &set, ptr::null_mut());
+    }
+}
+
+/// Looks up a signal by number, or by name with or without the `SIG` prefix
+/// and in any case.
+pub fn signal_number(spec: &str) -> Option<i32> {
+    if let Ok(number) = spec.parse::<i32>() {
+        return SIGNALS
+            .iter()
+            .any(|(_, signal)| *signal == number)
+            .then_some(number);
+    }
+    let name = spec.to_ascii_uppercase();
+    let name = name.strip_prefix("SIG").unwrap_or(&name);
+    SIGNALS
+        .iter()
+        .find(|(known, _)| *known == name)
+        .map(|(_, signal)| *signal)
+}
+
+/// The name of `signal` without the `SIG` prefix.
+fn signal_name(signal: i32) -> Option<&'static str> {
+    SIGNALS
+        .iter()
+        .find(|(_, known)| *known == signal)
+        .map(|(name, _)| *name)
+}
+
+/// Prints every signal with its number, five to a line, as `trap -l` and
+/// `kill -l` do.
+fn list_signals(out: &mut dyn Write) -> io::Result<()> {
+    let mut signals = SIGNALS;
+    signals.sort_by_key(|(_, signal)| *signal);
+    for row in signals.chunks(5) {
+        let row: Vec<_> = row
+            .iter()
+            .map(|(name, signal)| format!("{:2}) SIG{}", signal, name))
+            .collect();
+        writeln!(out, "{}", row.join("\t"))?;
+    }
+    Ok(())
+}
+
+/// Sets what the shell does when it gets `signal`.
+fn set_disposition(signal: i32, handler: libc::sighandler_t) -> io::Result<()> {
+    // SAFETY: `action` is fully initialized and `record` is async-signal-safe.
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handler;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(signal, &action, ptr::null_mut()) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// What the shell does with `signal` when it has no trap. Rust starts programs
+/// with `SIGPIPE` ignored, so that writing to a closed pipe fails instead.
+fn default_disposition(signal: i32) -> libc::sighandler_t {
+    match signal == libc::SIGPIPE || jobs::ignores(signal) {
+        true => libc::SIG_IGN,
+        false => libc::SIG_DFL,
+    }
+}
+
+/// When the commands of a trap are run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Condition {
+    /// When the shell or subshell exits.
+    Exit,
+    /// When the shell gets the signal.
+    Signal(i32),
+    /// Before every pipeline.
+    Debug,
+    /// After a pipeline fails, unless it is tested by `if`, `while`, `until`,
+    /// `&&` or `||`.
+    Err,
+}
+
+impl Condition {
+    /// Parses `EXIT` or `0`, `ERR`, `DEBUG` or a signal.
+    fn parse(spec: &str) -> Option<Self> {
+        match spec.to_ascii_uppercase().as_str() {
+            "EXIT" | "0" => Some(Self::Exit),
+            "DEBUG" => Some(Self::Debug),
+            "ERR" => Some(Self::Err),
+            _ => signal_number(spec).map(Self::Signal),
+        }
+    }
+    /// The name shown by `trap -p`.
+    fn name(self) -> String {
+        match self {
+            Self::Exit => String::from("EXIT"),
+            Self::Signal(signal) => format!("SIG{}", signal_name(signal).unwrap_or("?")),
+            Self::Debug => String::from("DEBUG"),
+            Self::Err => String::from("ERR"),
+        }
+    }
+}
+
+impl ExecContext {
+    /// Sets the commands run on `condition`, or resets it to what the shell did
+    /// before when there are none. Empty commands ignore a signal.
+    fn set_trap(&mut self, condition: Condition, handler: Option<Arc<AST>>) -> io::Result<()> {
+        if let Condition::Signal(signal) = condition {
+            let disposition = match &handler {
+                None => default_disposition(signal),
+                Some(handler) if handler.exprs.is_empty() => libc::SIG_IGN,
+                Some(_) => record as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            };
+            set_disposition(signal, disposition)?;
+            take_pending(signal);
+        }
+        match handler {
+            Some(handler) => self.traps.insert(condition, handler),
+            None => self.traps.remove(&condition),
+        };
+        Ok(())
+    }
+
+    /// Forgets the traps of the shell, as a subshell does. Signals that are
+    /// ignored stay ignored.
+    pub fn reset_traps(&mut self) {
+        self.traps.retain(|_, handler| handler.exprs.is_empty());
+    }
+
+    /// Runs the commands of the trap on `condition`, if there is one. `$?` is
+    /// left as it was unless they exit the shell. The `DEBUG` and `ERR` traps
+    /// are not set off by the commands of a trap.
+    fn run_trap(&mut self, condition: Condition, io: &Io) -> Result<(), ShellError> {
+        if self.in_trap && matches!(condition, Condition::Debug | Condition::Err) {
+            return Ok(());
+        }
+        let Some(handler) = self.traps.get(&condition).cloned() else {
+            return Ok(());
+        };
+        let status = self.last_status;
+        let in_trap = std::mem::replace(&mut self.in_trap, true);
+        let result = self.interpret_program(&handler, io);
+        self.in_trap = in_trap;
+        self.last_status = status;
+        result.map(|_| ())
+    }
+
+    /// Runs the traps of the signals that arrived since the last time, as done
+    /// between commands.
+    pub fn run_signal_traps(&mut self, io: &Io) -> Result<(), ShellError> {
+        if PENDING.load(Ordering::SeqCst) == 0 {
+            return Ok(());
+        }
+        let caught: Vec<Condition> = self
+            .traps
+            .keys()
+            .filter(
+                |condition| matches!(condition, Condition::Signal(signal) if take_pending(*signal)),
+            )
+            .copied()
+            .collect();
+        for condition in caught {
+            self.run_trap(condition, io)?;
+        }
+        Ok(())
+    }
+
+    /// Runs the `DEBUG` trap before `command`, which it and the `ERR` trap see
+    /// as `$BASH_COMMAND`.
+    pub fn debug_trap(&mut self, command: &str, io: &Io) -> Result<(), ShellError> {
+        let traced = [Condition::Debug, Condition::Err];
+        if self.in_trap || !traced.iter().any(|c| self.traps.contains_key(c)) {
+            return Ok(());
+        }
+        self.set_var("BASH_COMMAND", String::from(command));
+        self.run_trap(Condition::Debug, io)
+    }
+
+    /// Runs the `ERR` trap after a pipeline failed with `status`, unless the
+    /// pipeline is being tested or jumped out of its surroundings.
+    pub fn err_trap(&mut self, status: i32, io: &Io) -> Result<(), ShellError> {
+        if status == 0 || self.testing || self.flow.is_some() {
+            return Ok(());
+        }
+        self.last_status = status;
+        self.run_trap(Condition::Err, io)
+    }
+
+    /// Runs the `EXIT` trap as the shell exits with `status`, and returns the
+    /// status it exits with in the end, which the trap can change with `exit`.
+    pub fn exit_trap(&mut self, status: i32, io: &Io) -> i32 {
+        let Some(handler) = self.traps.remove(&Condition::Exit) else {
+            return status;
+        };
+        self.flow = None;
+        self.last_status = status;
+        if let Err(e) = self.interpret_program(&handler, io) {
+            io.report(&e);
+        }
+        match self.flow {
+            Some(Flow::Exit(status)) => status,
+            _ => status,
+        }
+    }
+
+    /// Prints the traps on `conditions`, or every trap, as commands that set
+    /// them again.
+    fn print_traps(&self, conditions: &[Condition], out: &mut dyn Write) -> io::Result<()> {
+        for (condition, handler) in &self.traps {
+            if conditions.is_empty() || conditions.contains(condition) {
+                writeln!(
+                    out,
+                    "trap -- {} {}",
+                    quote(&handler.source),
+                    condition.name()
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// `trap [-lp] [[command] condition...]`, runs `command` on every
+    /// condition. A `command` of `-`, or none, resets them and an empty one
+    /// ignores them. Without arguments, or with `-p`, the traps are listed.
+    pub fn trap(
+        &mut self,
+        args: &[String],
+        out: &mut dyn Write,
+        err: &mut dyn Write,
+    ) -> io::Result<i32> {
+        let mut args = args;
+        match args.first().map(String::as_str) {
+            Some("-l") => {
+                list_signals(out)?;
+                return Ok(0);
+            }
+            Some("-p") => {
+                let mut conditions = Vec::new();
+                for spec in &args[1..] {
+                    match Condition::parse(spec) {
+                        Some(condition) => conditions.push(condition),
+                        None => {
+                            writeln!(err, "trap: {}: invalid signal specification", spec)?;
+                            return Ok(1);
+                        }
+                    }
+                }
+                self.print_traps(&conditions, out)?;
+                return Ok(0);
+            }
+            Some("--") => args = &args[1..],
+            _ => {}
+        }
+        let Some((command, specs)) = args.split_first() else {
+            self.print_traps(&[], out)?;
+            return Ok(0);
+        };
+        // A lone condition, or conditions starting with a number, are reset.
+        let (command, specs) = match specs.is_empty() || command.parse::<u32>().is_ok() {
+            true => ("-", args),
+            false => (command.as_str(), specs),
+        };
+        let handler = match command {
+            "-" => None,
+            command => {
+                let mut lexer = Scanner::new(String::from(command));
+                match lexer.scan_tokens().and_then(|_| parse_program(lexer)) {
+                    Ok(ast) => Some(Arc::new(ast)),
+                    Err(e) => {
+                        writeln!(err, "trap: {}", e)?;
+                        return Ok(2);
+                    }
+                }
+            }
+        };
+        let mut status = 0;
+        for spec in specs {
+            let Some(condition) = Condition::parse(spec) else {
+                writeln!(err, "trap: {}: invalid signal specification", spec)?;
+                status = 1;
+                continue;
+            };
+            if let Err(e) = self.set_trap(condition, handler.clone()) {
+                writeln!(err, "trap: {}: {}", spec, e)?;
+                status = 1;
+            }
+        }
+        Ok(status)
+    }
+
+    /// `kill [-s signal|-n number|-signal] pid|job...`, sends a signal, `TERM`
+    /// by default, to processes or jobs. `kill -l [status|signal...]` lists the
+    /// signals, or turns statuses into names and names into numbers.
+    pub fn kill(
+        &mut self,
+        args: &[String],
+        out: &mut dyn Write,
+        err: &mut dyn Write,
+    ) -> io::Result<i32> {
+        const USAGE: &str = "kill: usage: kill [-s sigspec | -n signum | -sigspec] pid | jobspec ... or kill -l [sigspec]";
+        let mut signal = libc::SIGTERM;
+        let mut args = args;
+        let spec = match args.first().map(String::as_str) {
+            Some("-l" | "-L") if args.len() == 1 => {
+                list_signals(out)?;
+                return Ok(0);
+            }
+            Some("-l" | "-L") => {
+                let mut status = 0;
+                for spec in &args[1..] {
+                    // A status of a command killed by a signal stands for it.
+                    let named = match spec.parse::<i32>() {
+                        Ok(number) => signal_name(
+                            number.checked_sub(128).filter(|n| *n > 0).unwrap_or(number),
+                        )
+                        .map(String::from),
+                        Err(_) => signal_number(spec).map(|number| number.to_string()),
+                    };
+                    match named {
+                        Some(named) => writeln!(out, "{}", named)?,
+                        None => {
+                            writeln!(err, "kill: {}: invalid signal specification", spec)?;
+                            status = 1;
+                        }
+                    }
+                }
+                return Ok(status);
+            }
+            Some("-s" | "-n") => {
+                let spec = args.get(1);
+                args = args.get(2..).unwrap_or_default();
+                spec.map(String::as_str)
+            }
+            Some("--") => {
+                args = &args[1..];
+                None
+            }
+            Some(option) if option.starts_with('-') && option.len() > 1 => {
+                args = &args[1..];
+                Some(&option[1..])
+            }
+            _ => None,
+        };
+        if let Some(spec) = spec {
+            // Signal 0 only checks that the processes are there.
+            match signal_number(spec).or((spec == "0").then_some(0)) {
+                Some(number) => signal = number,
+                None => {
+                    writeln!(err, "kill: {}: invalid signal specification", spec)?;
+                    return Ok(1);
+                }
+            }
+        }
+        if args.first().is_some_and(|arg| arg == "--") {
+            args = &args[1..];
+        }
+        if args.is_empty() {
+            writeln!(err, "{}", USAGE)?;
+            return Ok(2);
+        }
+        let mut status = 0;
+        for target in args {
+            let sent = match target.parse::<i32>() {
+                _ if target.starts_with('%') => self.signal_job(target, signal),
+                // SAFETY: a plain system call.
+                Ok(pid) => match unsafe { libc::kill(pid, signal) } {
+                    0 => Ok(()),
+                    _ => Err(format!("({}) - {}", pid, io::Error::last_os_error())),
+                },
+                Err(_) => Err(format!("{}: arguments must be process or job IDs", target)),
+            };
+            if let Err(e) = sent {
+                writeln!(err, "kill: {}", e)?;
+                status = 1;
+            }
+        }
+        Ok(status)
+    }
+}
@@ -0,0 +1,414 @@
+use crate::ast::Cond;
+use crate::error::ShellError;
+use crate::glob;
+use crate::interpreter::{ExecContext, Io};
+use regex::Regex;
+use std::ffi::CString;
+use std::fs;
+use std::io::IsTerminal;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+
+/// Operators of `test` and `[[ ]]` that take a single operand.
+pub const UNARY: [&str; 16] = [
+    "-b", "-c", "-d", "-e", "-f", "-h", "-L", "-n", "-p", "-r", "-s", "-S", "-t", "-w", "-x", "-z",
+];
+
+/// Operators of `test` and `[[ ]]` that compare two operands, `[[ ]]` also has
+/// `=~`.
+pub const BINARY: [&str; 14] = [
+    "=", "==", "!=", "<", ">", "-eq", "-ne", "-lt", "-le", "-gt", "-ge", "-nt", "-ot", "-ef",
+];
+
+/// Whether `op` compares integers.
+fn is_integer_op(op: &str) -> bool {
+    matches!(op, "-eq" | "-ne" | "-lt" | "-le" | "-gt" | "-ge")
+}
+
+/// Compares two integers with one of the integer operators.
+fn compare(op: &str, left: i64, right: i64) -> bool {
+    match op {
+        "-eq" => left == right,
+        "-ne" => left != right,
+        "-lt" => left < right,
+        "-le" => left <= right,
+        "-gt" => left > right,
+        _ => left >= right,
+    }
+}
+
+/// Whether the user may access `path` as `mode`, one of `R_OK`, `W_OK` and
+/// `X_OK`.
+fn access(path: &Path, mode: i32) -> bool {
+    let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // SAFETY: `path` is a valid NUL-terminated string for the whole call.
+    unsafe { libc::access(path.as_ptr(), mode) == 0 }
+}
+
+/// A recursive descent parser over the arguments of `test`, which evaluates
+/// them as it goes.
+struct Test<'a> {
+    ctx: &'a ExecContext,
+    io: &'a Io,
+    args: &'a [String],
+    current: usize,
+}
+
+impl Test<'_> {
+    fn eat(&mut self, arg: &str) -> bool {
+        if self.args.get(self.current).is_some_and(|a| a == arg) {
+            self.current += 1;
+            return true;
+        }
+        false
+    }
+
+    /// or → and ( "-o" and )*
+    fn or(&mut self) -> Result<bool, String> {
+        let mut value = self.and()?;
+        while self.eat("-o") {
+            value |= self.and()?;
+        }
+        Ok(value)
+    }
+
+    /// and → not ( "-a" not )*
+    fn and(&mut self) -> Result<bool, String> {
+        let mut value = self.not()?;
+        while self.eat("-a") {
+            value &= self.not()?;
+        }
+        Ok(value)
+    }
+
+    /// not → "!" not | primary
+    fn not(&mut self) -> Result<bool, String> {
+        // A lone `!` is just a non-empty string.
+        if self.current + 1 < self.args.len() && self.eat("!") {
+            return Ok(!self.not()?);
+        }
+        self.primary()
+    }
+
+    /// primary → word BINARY_OP word | "(" or ")" | UNARY_OP word | word
+    fn primary(&mut self) -> Result<bool, String> {
+        let rest = &self.args[self.current..];
+        match rest {
+            [] => Err(String::from("argument expected")),
+            [left, op, right, ..] if BINARY.contains(&op.as_str()) => {
+                self.current += 3;
+                self.ctx.binary_test(op, left, right, |n| {
+                    n.trim()
+                        .parse()
+                        .map_err(|_| format!("{}: integer expression expected", n))
+                })
+            }
+            [open, ..] if open == "(" && rest.len() > 1 => {
+                self.current += 1;
+                let value = self.or()?;
+                match self.eat(")") {
+                    true => Ok(value),
+                    false => Err(String::from("`)' expected")),
+                }
+            }
+            [op, arg, ..] if UNARY.contains(&op.as_str()) => {
+                self.current += 2;
+                Ok(self.ctx.unary_test(op, arg, self.io))
+            }
+            [word, ..] => {
+                self.current += 1;
+                Ok(!word.is_empty())
+            }
+        }
+    }
+}
+
+impl ExecContext {
+    /// Evaluates an operator that takes one operand. Files are looked up
+    /// relative to the current directory.
+    fn unary_test(&self, op: &str, arg: &str, io: &Io) -> bool {
+        let path = self.cur_dir.join(arg);
+        let meta = fs::metadata(&path);
+        match op {
+            "-n" => !arg.is_empty(),
+            "-z" => arg.is_empty(),
+            "-e" => meta.is_ok(),
+            "-f" => meta.is_ok_and(|m| m.is_file()),
+            "-d" => meta.is_ok_and(|m| m.is_dir()),
+            "-s" => meta.is_ok_and(|m| m.len() > 0),
+            "-b" => meta.is_ok_and(|m| m.file_type().is_block_device()),
+            "-c" => meta.is_ok_and(|m| m.file_type().is_char_device()),
+            "-p" => meta.is_ok_and(|m| m.file_type().is_fifo()),
+            "-S" => meta.is_ok_and(|m| m.file_type().is_socket()),
+            "-h" | "-L" => fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_symlink()),
+            "-r" => access(&path, libc::R_OK),
+            "-w" => access(&path, libc::W_OK),
+            "-x" => access(&path, libc::X_OK),
+            // The descriptor is looked up among the command's own streams.
+            "-t" => arg
+                .trim()
+                .parse()
+                .ok()
+                .and_then(|fd| io.get(fd).ok())
+                .is_some_and(|fd| fd.is_terminal()),
+            _ => false,
+        }
+    }
+
+    /// Evaluates an operator that compares two operands as strings, integers
+    /// read with `int`, or files.
+    fn binary_test(
+        &self,
+        op: &str,
+        left: &str,
+        right: &str,
+        mut int: impl FnMut(&str) -> Result<i64, String>,
+    ) -> Result<bool, String> {
+        if is_integer_op(op) {
+            return Ok(compare(op, int(left)?, int(right)?));
+        }
+        let meta = |file: &str| fs::metadata(self.cur_dir.join(file));
+        Ok(match op {
+            "=" | "==" => left == right,
+            "!=" => left != right,
+            "<" => left < right,
+            ">" => left > right,
+            // A file that exists is newer than one that does not.
+            "-nt" | "-ot" => {
+                let (newer, older) = match op {
+                    "-nt" => (meta(left), meta(right)),
+                    _ => (meta(right), meta(left)),
+                };
+                match (newer, older) {
+                    (Ok(newer), Ok(older)) => {
+                        newer.mtime() > older.mtime()
+                            || newer.mtime() == older.mtime()
+                                && newer.mtime_nsec() > older.mtime_nsec()
+                    }
+                    (Ok(_), Err(_)) => true,
+                    _ => false,
+                }
+            }
+            "-ef" => match (meta(left), meta(right)) {
+                (Ok(l), Ok(r)) => l.dev() == r.dev() && l.ino() == r.ino(),
+                _ => false,
+            },
+            _ => return Err(format!("{}: binary operator expected", op)),
+        })
+    }
+
+    /// Evaluates the arguments of `test`, or of `[` without its closing `]`.
+    pub fn test(&self, args: &[String], io: &Io) -> Result<bool, String> {
+        if args.is_empty() {
+            return Ok(false);
+        }
+        let mut test = Test {
+            ctx: self,
+            io,
+            args,
+            current: 0,
+        };
+        let value = test.or()?;
+        match args.get(test.current) {
+            None => Ok(value),
+            Some(arg) => Err(format!("{}: unexpected argument", arg)),
+        }
+    }
+
+    /// Evaluates the condition of a `[[ ]]` command. Unlike `test`, `==` and
+    /// `!=` match a pattern, `=~` matches a regex and the operands of integer
+    /// comparisons are arithmetic expressions.
+    pub fn eval_cond(&mut self, cond: &Cond, io: &Io) -> Result<bool, ShellError> {
+        Ok(match cond {
+            Cond::Word(word) => !self.expand_string(word)?.is_empty(),
+            Cond::Unary { op, word } => {
+                let arg = self.expand_string(word)?;
+                self.unary_test(op, &arg, io)
+            }
+            Cond::Not(cond) => !self.eval_cond(cond, io)?,
+            Cond::And(left, right) => self.eval_cond(left, io)? && self.eval_cond(right, io)?,
+            Cond::Or(left, right) => self.eval_cond(left, io)? || self.eval_cond(right, io)?,
+            Cond::Binary { op, left, right } => {
+                let left = self.expand_string(left)?;
+                match op.as_str() {
+                    "=" | "==" => glob::matches(&self.expand_pattern(right)?, &left),
+                    "!=" => !glob::matches(&self.expand_pattern(right)?, &left),
+                    "=~" => self.regex_match(&left, right)?,
+                    op if is_integer_op(op) => {
+                        let right = self.expand_string(right)?;
+                        let mut int = |expr: &str| {
+                            self.arithmetic(expr).map_err(|e| ShellError::Test {
+                                message: e.to_string(),
+                            })
+                        };
+                        let (left, right) = (int(&left)?, int(&right)?);
+                        compare(op, left, right)
+                    }
+                    op => {
+                        let right = self.expand_string(right)?;
+                        self.binary_test(op, &left, &right, |_| Ok(0))
+                            .map_err(|message| ShellError::Test { message })?
+                    }
+                }
+            }
+        })
+    }
+
+    /// Matches `text` against the regex written as `word`, whose quoted parts
+    /// match literally. The match and the text of every group are stored in the
+    /// `BASH_REMATCH` array.
+    fn regex_match(&mut self, text: &str, word: &str) -> Result<bool, ShellError> {
+        let pattern = self.expand_regex(word)?;
+        let regex = Regex::new(&pattern).map_err(|_| ShellError::Test {
+            message: format!("{}: invalid regular expression", pattern),
+        })?;
+        let Some(captures) = regex.captures(text) else {
+            self.arrays.remove("BASH_REMATCH");
+            return Ok(false);
+        };
+        let groups = captures
+            .iter()
+            .map(|group| String::from(group.map_or("", |group| group.as_str())))
+            .collect();
+        self.arrays.insert(String::from("BASH_REMATCH"), groups);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interpreter::{ExecContext, Io};
+    use crate::parser::parse_program;
+    use crate::scanner::Scanner;
+    use std::fs::{self, File};
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+
+    fn shell() -> ExecContext {
+        ExecContext::new(Path::new("/"), vec![String::from("wts")])
+    }
+
+    /// Runs `test` with `args` split on spaces.
+    fn test(args: &str) -> Result<bool, String> {
+        let args: Vec<String> = args.split(' ').map(String::from).collect();
+        shell().test(&args, &Io::default())
+    }
+
+    /// The status of `[[ cond ]]`, with errors going to `/dev/null`.
+    fn cond(ctx: &mut ExecContext, cond: &str) -> i32 {
+        let mut lexer = Scanner::new(format!("[[ {} ]]", cond));
+        lexer.scan_tokens().unwrap();
+        let ast = parse_program(lexer).unwrap();
+        let mut io = Io::default();
+        io.set(2, File::create("/dev/null").unwrap().into());
+        ctx.interpret_program(&ast, &io)
+            .unwrap_or_else(|e| e.status())
+    }
+
+    #[test]
+    fn test_operators() {
+        let cases = [
+            ("abc", true),
+            ("-n abc", true),
+            ("-z abc", false),
+            ("! -z abc", true),
+            ("! !", false),
+            ("a = a", true),
+            ("a != a", false),
+            ("a* = abc", false),
+            ("2 -lt 10", true),
+            ("2 < 10", false),
+            ("-3 -ge -3", true),
+            // `-a` binds tighter than `-o`, and `!` tighter than both.
+            ("1 -eq 1 -o 1 -eq 2 -a 1 -eq 2", true),
+            ("( 1 -eq 1 -o 1 -eq 2 ) -a 1 -eq 2", false),
+            ("! 1 = 1 -o 2 = 2", true),
+            ("! 1 = 1 -a 2 = 2", false),
+            ("-d /", true),
+            ("-f /", false),
+            ("-e /nonexistent", false),
+        ];
+        for (args, expected) in cases {
+            assert_eq!(test(args), Ok(expected), "test {}", args);
+        }
+        assert!(shell().test(&[], &Io::default()) == Ok(false));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            test("1 -lt x"),
+            Err(String::from("x: integer expression expected"))
+        );
+        assert_eq!(test("a = b c"), Err(String::from("c: unexpected argument")));
+        assert!(test("( a").is_err());
+    }
+
+    #[test]
+    fn newer_and_older() {
+        let dir = std::env::temp_dir().join(format!("wts-test-nt-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (old, new) = (dir.join("old"), dir.join("new"));
+        let now = SystemTime::now();
+        File::create(&old)
+            .unwrap()
+            .set_modified(now - Duration::from_secs(60))
+            .unwrap();
+        File::create(&new).unwrap().set_modified(now).unwrap();
+        let (old, new) = (old.to_str().unwrap(), new.to_str().unwrap());
+        let missing = dir.join("missing");
+        let missing = missing.to_str().unwrap();
+        assert_eq!(test(&format!("{} -nt {}", new, old)), Ok(true));
+        assert_eq!(test(&format!("{} -nt {}", old, new)), Ok(false));
+        assert_eq!(test(&format!("{} -ot {}", old, new)), Ok(true));
+        // An existing file is newer than one that does not exist.
+        assert_eq!(test(&format!("{} -nt {}", old, missing)), Ok(true));
+        assert_eq!(test(&format!("{} -ot {}", missing, old)), Ok(true));
+        assert_eq!(test(&format!("{} -ef {}", old, old)), Ok(true));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn double_bracket_patterns() {
+        let mut ctx = shell();
+        ctx.set_var("x", String::from("a b"));
+        let cases = [
+            ("abc == a*", 0),
+            ("abc == \"a*\"", 1),
+            ("'a*' == \"a*\"", 0),
+            ("abc != a?c", 1),
+            ("abc == [ab]bc", 0),
+            // Words are not split, so an unquoted variable with spaces is fine.
+            ("$x == 'a b'", 0),
+            ("-n $x", 0),
+            ("! -z x && -n x", 0),
+            ("-z x || a == a", 0),
+            ("( a == b || b == b ) && ! c == d", 0),
+            ("a < b", 0),
+            ("b < a", 1),
+            ("1+1 -eq 2", 0),
+        ];
+        for (text, status) in cases {
+            assert_eq!(cond(&mut ctx, text), status, "[[ {} ]]", text);
+        }
+    }
+
+    #[test]
+    fn double_bracket_regex() {
+        let mut ctx = shell();
+        assert_eq!(cond(&mut ctx, "foo123 =~ ^foo([0-9]+)$"), 0);
+        assert_eq!(
+            ctx.arrays["BASH_REMATCH"],
+            [String::from("foo123"), String::from("123")]
+        );
+        // Quoted parts match literally.
+        assert_eq!(cond(&mut ctx, "foo =~ \"o+\""), 1);
+        assert!(!ctx.arrays.contains_key("BASH_REMATCH"));
+        assert_eq!(cond(&mut ctx, "a.c =~ \".\""), 0);
+        assert_eq!(cond(&mut ctx, "abc =~ \".\""), 1);
+        assert_eq!(cond(&mut ctx, "a =~ ("), 2);
+    }
+}
@@ -1,29 +1,35 @@
+use crate::error::Span;
+
 #[derive(Debug)]
 pub struct Token {
     pub t_type: TokenType,
     pub literal: WTSType,
     pub lexeme: String,
-    pub line: usize,
+    /// Where the lexeme is in the source.
+    pub span: Span,
 }
 
 impl Token {
-    pub fn new(t_type: TokenType, literal: WTSType, lexeme: String, line: usize) -> Self {
+    pub fn new(t_type: TokenType, literal: WTSType, lexeme: String, span: Span) -> Self {
         Self {
             t_type,
             literal,
             lexeme,
-            line,
+            span,
         }
     }
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug)]
 pub enum WTSType {
+    /// The body of a heredoc, held by the token of its delimiter.
     String(String),
-    //Number(i32),
+    Number(i32),
     //EXPR,
     NONE,
 }
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TokenType {
     // Single-character tokens.
@@ -36,6 +42,10 @@ pub enum TokenType {
     //MINUS,
     //PLUS,
     Semicolon,
+    DoubleSemicolon,
+    SemicolonAnd,
+    DoubleSemicolonAnd,
+    Newline,
     Pound,
     //Star,
 
@@ -44,23 +54,78 @@ pub enum TokenType {
     LongFlag,
     RedirLeft,
     RedirRight,
+    ClobberRedirRight,
+    ReadWriteRedir,
     DoubleRedirRight,
     DoubleRedirLeft,
+    DoubleRedirLeftDash,
+    HereString,
+    DupRedirLeft,
+    DupRedirRight,
+    AndRedirRight,
+    AndDoubleRedirRight,
     Pipe,
+    AndIf,
+    OrIf,
+    Ampersand,
 
     // Literals.
     Word,
-    String,
+    IoNumber,
+    /// A whole `(( ... ))` command, scanned as one token.
+    Arithmetic,
     // Keywords.
 
     //SCRIPT,
     //AND,
-    //ELSE,
-    //FOR,
-    //IF,
+    If,
+    Then,
+    Elif,
+    Else,
+    Fi,
+    While,
+    Until,
+    For,
+    In,
+    Do,
+    Done,
+    Case,
+    Esac,
+    Function,
+    LeftBrace,
+    RightBrace,
+    DoubleLeftBracket,
+    DoubleRightBracket,
     //OR,
     //TRUE,
     //VAR,
-    //WHILE,
     EOF,
 }
+
+impl TokenType {
+    /// Whether the token is a reserved word, which is only special where a
+    /// command name could go and is an ordinary word everywhere else.
+    pub fn is_keyword(self) -> bool {
+        matches!(
+            self,
+            TokenType::If
+                | TokenType::Then
+                | TokenType::Elif
+                | TokenType::Else
+                | TokenType::Fi
+                | TokenType::While
+                | TokenType::Until
+                | TokenType::For
+                | TokenType::In
+                | TokenType::Do
+                | TokenType::Done
+                | TokenType::Case
+                | TokenType::Esac
+                | TokenType::Function
+                | TokenType::LeftBrace
+                | TokenType::RightBrace
+                | TokenType::DoubleLeftBracket
+                | TokenType::DoubleRightBracket
+        )
+    }
+}
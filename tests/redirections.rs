@@ -0,0 +1,24 @@
+use std::{env, fs, process::Command};
+
+/// Numbered redirections given out of order on a pipeline stage must each end
+/// up on their own descriptor, whatever descriptors the opened files got.
+#[test]
+fn out_of_order_numbered_redirections_in_pipeline() {
+    let dir = env::temp_dir().join(format!("wts-redirections-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let script = "sh -c 'echo three >&3; echo four >&4' 4>b 3>a | cat\n\
+                  echo | sh -c 'echo three >&3; echo four >&4; echo six >&6' 6>f 3>d 4>e | cat";
+    let status = Command::new(env!("CARGO_BIN_EXE_what-the-shell"))
+        .args(["-c", script])
+        .current_dir(&dir)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+    assert_eq!(read("a"), "three\n");
+    assert_eq!(read("b"), "four\n");
+    assert_eq!(read("d"), "three\n");
+    assert_eq!(read("e"), "four\n");
+    assert_eq!(read("f"), "six\n");
+    fs::remove_dir_all(&dir).unwrap();
+}